@@ -1,395 +1,8703 @@
 use derive_builder::Builder;
-use std::io::Cursor;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
+#[cfg(feature = "tokio")]
+use tokio_util::sync::CancellationToken;
 
+/// Errors returned by Packer operations
+///
+/// This enum is `#[non_exhaustive]`: new variants may be added in minor
+/// releases, so matches on it need a catch-all arm.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum PackerError {
-    #[error("Failed to execute Packer command: {0}")]
-    ExecutionError(String),
-    #[error("Failed to find Packer executable")]
-    NotFound,
+    /// Packer ran but failed
+    ///
+    /// `exit_code` is `None` if packer was killed by a signal or never got to
+    /// exit. `stderr` holds the end of packer's stderr, at most 4096 bytes
+    /// unless changed with [`Packer::with_stderr_limit`].
+    #[error("Failed to execute Packer command: {message}")]
+    ExecutionError {
+        message: String,
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    /// No packer binary was found; lists the locations that were tried
+    #[error(transparent)]
+    NotFound(PackerNotFoundError),
     #[error("Invalid configuration: {0}")]
     ConfigError(String),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Packer {found} is older than the required version {required}")]
+    VersionError { found: String, required: String },
+    #[error("Packer command timed out after {elapsed:?}")]
+    TimeoutError { elapsed: std::time::Duration },
+    #[error("Templates are not formatted: {}", display_paths(files))]
+    FmtCheckFailed { files: Vec<PathBuf> },
+    /// A downloaded file does not match its published SHA-256 checksum
+    #[error("Checksum mismatch for {file}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    /// [`Packer::hcl2_upgrade_dir`] stopped at a template it could not upgrade
+    #[error(transparent)]
+    PartialUpgrade(Box<PartialUpgradeError>),
+    /// Packer doesn't know the plugin it was asked to remove
+    #[error("Plugin {0} is not installed")]
+    PluginNotFound(String),
+    /// A command was stopped through its cancellation token, see `Packer::build_cancellable`
+    #[error("Packer command was cancelled")]
+    Cancelled,
+    /// Several operations of a batch failed; each error is labelled with what it relates to
+    #[error("{}", display_errors(.0))]
+    MultipleErrors(Vec<(String, PackerError)>),
+}
+
+/// Where a packer binary was looked for, see [`PackerError::NotFound`]
+#[derive(Error, Debug, Clone, Default, PartialEq, Eq)]
+#[error("Failed to find Packer executable{}", searched_suffix(searched_paths))]
+pub struct PackerNotFoundError {
+    /// Every path checked, in order; empty when there was nothing to search
+    pub searched_paths: Vec<PathBuf>,
+}
+
+fn searched_suffix(paths: &[PathBuf]) -> String {
+    if paths.is_empty() {
+        return String::new();
+    }
+    format!(" (searched {})", display_paths(paths))
+}
+
+impl PackerError {
+    /// The exit code packer failed with, if this is a [`PackerError::ExecutionError`]
+    ///
+    /// Packer exits with `1` when a build fails and with `2` on usage errors.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            PackerError::ExecutionError { exit_code, .. } => *exit_code,
+            _ => None,
+        }
+    }
+
+    /// Keep at most the last `limit` bytes of the stderr of an [`PackerError::ExecutionError`]
+    fn truncate_stderr(self, limit: usize) -> Self {
+        match self {
+            PackerError::ExecutionError {
+                message,
+                exit_code,
+                stderr,
+            } => PackerError::ExecutionError {
+                message: truncate_front(message, limit),
+                exit_code,
+                stderr: truncate_front(stderr, limit),
+            },
+            other => other,
+        }
+    }
+
+    /// A [`PackerError::NotFound`] listing `searched_paths`
+    fn not_found(searched_paths: Vec<PathBuf>) -> Self {
+        PackerError::NotFound(PackerNotFoundError { searched_paths })
+    }
+
+    /// An execution failure that did not come from packer's exit status
+    fn execution(message: impl Into<String>) -> Self {
+        PackerError::ExecutionError {
+            message: message.into(),
+            exit_code: None,
+            stderr: String::new(),
+        }
+    }
+
+    /// Combine the failures of a batch operation
+    ///
+    /// Returns `Ok(())` if nothing failed and [`PackerError::MultipleErrors`]
+    /// otherwise. Nested `MultipleErrors` are flattened into the result, their
+    /// labels prefixed with the outer one; empty ones disappear.
+    pub fn from_errors(errors: Vec<(String, PackerError)>) -> Result<()> {
+        let mut flattened = Vec::with_capacity(errors.len());
+        for (label, err) in errors {
+            match err {
+                PackerError::MultipleErrors(nested) => flattened.extend(
+                    nested
+                        .into_iter()
+                        .map(|(inner, err)| (format!("{}: {}", label, inner), err)),
+                ),
+                err => flattened.push((label, err)),
+            }
+        }
+        if flattened.is_empty() {
+            return Ok(());
+        }
+
+        Err(PackerError::MultipleErrors(flattened))
+    }
+}
+
+/// The templates [`Packer::hcl2_upgrade_dir`] upgraded before one failed
+#[derive(Error, Debug)]
+#[error(
+    "Failed to upgrade {} after upgrading {} templates: {error}",
+    failed_template.display(),
+    succeeded.len()
+)]
+pub struct PartialUpgradeError {
+    /// `(original, output)` paths of the templates that were upgraded
+    pub succeeded: Vec<(PathBuf, PathBuf)>,
+    #[source]
+    pub error: PackerError,
+    pub failed_template: PathBuf,
+}
+
+/// Malformed or unserializable JSON is a configuration problem
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for PackerError {
+    fn from(err: serde_json::Error) -> Self {
+        PackerError::ConfigError(err.to_string())
+    }
+}
+
+/// Malformed TOML is a configuration problem
+#[cfg(feature = "serde")]
+impl From<toml::de::Error> for PackerError {
+    fn from(err: toml::de::Error) -> Self {
+        PackerError::ConfigError(err.to_string())
+    }
+}
+
+/// Options that can't be written as TOML are a configuration problem
+#[cfg(feature = "serde")]
+impl From<toml::ser::Error> for PackerError {
+    fn from(err: toml::ser::Error) -> Self {
+        PackerError::ConfigError(err.to_string())
+    }
+}
+
+fn display_errors(errors: &[(String, PackerError)]) -> String {
+    let mut message = format!("{} of the operations failed", errors.len());
+    for (label, err) in errors {
+        message.push_str(&format!("\n  {}: {}", label, err));
+    }
+    message
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 type Result<T> = std::result::Result<T, PackerError>;
 
+/// Handle to a Packer binary
+///
+/// `Packer` is `Send + Sync`. Every method spawns its own independent child
+/// process, so one instance can be shared across threads (e.g. behind an
+/// `Arc<Packer>`) and called concurrently.
 #[derive(Debug, Clone)]
 pub struct Packer {
     executable: PathBuf,
     working_dir: Option<PathBuf>,
+    env_vars: Vec<(String, String)>,
+    /// Variables that must not be inherited from the current process
+    env_removals: Vec<String>,
+    stdout_writer: Option<SharedWriter>,
+    stderr_writer: Option<SharedWriter>,
+    /// How long [`Packer::check_health`] waits for `packer version`
+    health_check_timeout: Duration,
+    /// Most bytes of stderr kept in a [`PackerError::ExecutionError`]
+    stderr_limit: usize,
+    launcher: Arc<dyn ProcessLauncher>,
+    /// Parsed inspections by template path and modification time, see [`Packer::with_inspect_cache`]
+    inspect_cache: Option<Arc<InspectCache>>,
+    /// The version [`Packer::require_version`] probed, so it only runs `packer version` once
+    version: OnceLock<PackerVersion>,
+}
+
+type InspectCache = Mutex<HashMap<(PathBuf, SystemTime), TemplateInspection>>;
+
+/// A writer that receives a copy of packer's output, shared between clones of a [`Packer`]
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl SharedWriter {
+    fn write_all(&self, buf: &[u8]) -> std::io::Result<()> {
+        let mut writer = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(buf)?;
+        writer.flush()
+    }
+}
+
+impl std::fmt::Debug for SharedWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedWriter")
+    }
+}
+
+/// Runs the processes a [`Packer`] starts; swap it out with [`Packer::with_launcher`]
+///
+/// Commands run to completion go through [`ProcessLauncher::launch`]. Those
+/// whose output is streamed or that are killed on a timeout go through
+/// [`ProcessLauncher::spawn`]. The `tokio` feature's async methods always
+/// use `tokio::process` directly.
+pub trait ProcessLauncher: std::fmt::Debug + Send + Sync {
+    /// Run `cmd` to completion and collect its output, like [`Command::output`]
+    ///
+    /// [`Packer`] configures all three stdio streams before calling this,
+    /// e.g. inherited stdout for commands whose output the user should see.
+    fn launch(&self, cmd: &mut Command) -> std::io::Result<std::process::Output>;
+
+    /// Start `cmd` without waiting for it, like [`Command::spawn`]
+    fn spawn(&self, cmd: &mut Command) -> std::io::Result<std::process::Child>;
+}
+
+/// The default [`ProcessLauncher`], which runs commands as real processes
+///
+/// On Unix, SIGTERM and SIGINT are forwarded to commands that run in their own
+/// process group while they run, see [`Packer::build`]. A custom launcher
+/// that calls [`Command::output`] and [`Command::spawn`] directly opts out of
+/// that.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RealProcessLauncher;
+
+impl ProcessLauncher for RealProcessLauncher {
+    fn launch(&self, cmd: &mut Command) -> std::io::Result<std::process::Output> {
+        let mut child = cmd.spawn()?;
+        let _forwarding = forward_signals(&mut child)?;
+        child.wait_with_output()
+    }
+
+    fn spawn(&self, cmd: &mut Command) -> std::io::Result<std::process::Child> {
+        cmd.spawn()
+    }
+}
+
+/// A [`ProcessLauncher`] for tests that checks the arguments instead of running anything
+///
+/// [`ProcessLauncher::launch`] returns `output` if the command's arguments
+/// equal `expected_args`, and an [`std::io::ErrorKind::InvalidInput`] error
+/// naming both otherwise. [`ProcessLauncher::spawn`] always fails with
+/// [`std::io::ErrorKind::Unsupported`], so methods that stream output or
+/// take a timeout can't be tested with it. Only available with the
+/// `test-support` feature.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockProcessLauncher {
+    pub expected_args: Vec<String>,
+    pub output: std::process::Output,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl MockProcessLauncher {
+    /// Expect `args` and answer with a successful exit and `stdout`
+    pub fn new<S: Into<String>>(args: impl IntoIterator<Item = S>, stdout: &str) -> Self {
+        MockProcessLauncher {
+            expected_args: args.into_iter().map(Into::into).collect(),
+            output: std::process::Output {
+                status: exit_status(0),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl ProcessLauncher for MockProcessLauncher {
+    fn launch(&self, cmd: &mut Command) -> std::io::Result<std::process::Output> {
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        if args != self.expected_args {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "packer called with {:?}, expected {:?}",
+                    args, self.expected_args
+                ),
+            ));
+        }
+
+        Ok(self.output.clone())
+    }
+
+    fn spawn(&self, _cmd: &mut Command) -> std::io::Result<std::process::Child> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "MockProcessLauncher cannot spawn processes",
+        ))
+    }
+}
+
+/// An [`std::process::ExitStatus`] for a process that exited with `code`
+#[cfg(all(unix, any(test, feature = "test-support")))]
+fn exit_status(code: i32) -> std::process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+
+    std::process::ExitStatus::from_raw(code << 8)
+}
+
+/// An [`std::process::ExitStatus`] for a process that exited with `code`
+#[cfg(all(windows, any(test, feature = "test-support")))]
+fn exit_status(code: i32) -> std::process::ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+
+    std::process::ExitStatus::from_raw(code as u32)
 }
 
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, PartialEq, Builder)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct BuildOptions {
+    /// Maximum number of builds packer runs at once; `0` means no limit
     #[builder(default)]
-    pub parallel_builds: Option<i32>,
+    pub parallel_builds: Option<u32>,
     #[builder(default)]
     pub debug: bool,
     #[builder(default)]
     pub force: bool,
     #[builder(default)]
     pub timestamp_ui: bool,
+    /// Colored output; `Some(false)` passes `-color=false`
+    ///
+    /// An explicit choice always wins: `Some(true)` keeps color on even when
+    /// `PACKER_NO_COLOR` is set, by not passing that variable on to packer.
+    /// With `None` (the default) a non-empty `PACKER_NO_COLOR` in packer's
+    /// environment (this process's, or one set with [`Packer::with_env`])
+    /// turns color off, as it does for packer itself.
+    #[builder(setter(into, strip_option), default)]
+    pub color: Option<bool>,
+    /// Template variables; use [`VarValue::Secret`] for values that must not be logged
+    #[builder(setter(custom), default)]
+    #[cfg_attr(feature = "serde", serde(with = "vars_map"))]
+    pub vars: Vec<(String, VarValue)>,
+    #[builder(default)]
+    pub var_files: Vec<PathBuf>,
+    /// Only run the named builds, e.g. `amazon-ebs.my_ami`
+    #[builder(default)]
+    pub only: Vec<String>,
+    /// Run every build except the named ones
+    #[builder(default)]
+    pub except: Vec<String>,
+    /// What to do when a build step fails
+    #[builder(default)]
+    pub on_error: Option<OnError>,
+    /// Write packer's machine-readable stdout to this file instead of memory
+    ///
+    /// Relative paths are resolved against the working directory. The file is
+    /// read back for the [`BuildResult`] once the build ends and is left in
+    /// place afterwards. Configured stdout writers get no copy.
+    ///
+    /// Every build method honours it except `Packer::build_stream`, which
+    /// hands each line to the caller and assembles no [`BuildResult`]; it
+    /// fails with [`PackerError::ConfigError`] when this is set.
+    #[builder(setter(into, strip_option), default)]
+    pub machine_readable_log_file: Option<PathBuf>,
+    /// Set `CHECKPOINT_DISABLE=1` for packer, stopping its update checks
+    ///
+    /// Unlike packer itself this defaults to `true`: the crate picks and
+    /// installs the packer binary, so the check only tells you what you
+    /// already control, and it adds latency to every build where the network
+    /// is restricted. Set it to `false` to let packer call home.
+    #[builder(default = "true")]
+    pub disable_checkpoint: bool,
+    /// Start packer with an empty environment instead of inheriting this process's
+    ///
+    /// Only the variables in [`ISOLATED_ENV_PASSTHROUGH`] and `env_whitelist`
+    /// are passed on, plus those set on the instance with [`Packer::with_env`]
+    /// and friends. This keeps credentials, proxies and tokens of the calling
+    /// process away from the build, but plugins that read their credentials
+    /// from the environment (`AWS_ACCESS_KEY_ID`, `GOOGLE_APPLICATION_CREDENTIALS`,
+    /// ...) will fail unless those are whitelisted or set explicitly.
+    #[builder(default)]
+    pub isolated_env: bool,
+    /// Extra variables to pass through from this process when `isolated_env` is set
+    #[builder(default)]
+    pub env_whitelist: Vec<String>,
+}
+
+/// Variables a [`BuildOptions::isolated_env`] build still inherits
+#[cfg(not(windows))]
+pub const ISOLATED_ENV_PASSTHROUGH: &[&str] = &["PATH", "HOME", "TMPDIR"];
+
+/// Variables a [`BuildOptions::isolated_env`] build still inherits
+#[cfg(windows)]
+pub const ISOLATED_ENV_PASSTHROUGH: &[&str] = &[
+    "PATH",
+    "HOME",
+    "TMPDIR",
+    "SYSTEMROOT",
+    "TEMP",
+    "TMP",
+    "USERPROFILE",
+];
+
+impl BuildOptionsBuilder {
+    /// Set the template variables; values can be plain strings or [`SecretVar`]s
+    pub fn vars<K, V>(&mut self, vars: impl IntoIterator<Item = (K, V)>) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<VarValue>,
+    {
+        self.vars = Some(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        );
+        self
+    }
+
+    /// Build the options and check them with [`BuildOptions::validate`]
+    pub fn build_and_validate(&self) -> Result<BuildOptions> {
+        let options = self
+            .build()
+            .map_err(|e| PackerError::ConfigError(e.to_string()))?;
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+/// A variable value that is redacted in `Debug` and `Display` output
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "SecretRepr", into = "SecretRepr"))]
+pub struct SecretVar(String);
+
+impl SecretVar {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// The actual secret value
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for SecretVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretVar(\"***\")")
+    }
+}
+
+impl std::fmt::Display for SecretVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Secrets are written as `{ "secret": "..." }` so they stay distinguishable from plain values
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct SecretRepr {
+    secret: String,
+}
+
+#[cfg(feature = "serde")]
+impl From<SecretRepr> for SecretVar {
+    fn from(repr: SecretRepr) -> Self {
+        SecretVar(repr.secret)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<SecretVar> for SecretRepr {
+    fn from(secret: SecretVar) -> Self {
+        SecretRepr { secret: secret.0 }
+    }
+}
+
+/// The value of a template variable
+///
+/// Both variants are passed to packer as-is; only `Secret` is redacted when
+/// the options are printed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum VarValue {
+    Plain(String),
+    Secret(SecretVar),
+}
+
+impl VarValue {
+    /// The value passed to packer, including the actual value of secrets
+    pub fn expose(&self) -> &str {
+        match self {
+            VarValue::Plain(value) => value,
+            VarValue::Secret(secret) => secret.expose_secret(),
+        }
+    }
+}
+
+impl std::fmt::Display for VarValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VarValue::Plain(value) => f.write_str(value),
+            VarValue::Secret(secret) => secret.fmt(f),
+        }
+    }
+}
+
+impl From<String> for VarValue {
+    fn from(value: String) -> Self {
+        VarValue::Plain(value)
+    }
+}
+
+impl From<&str> for VarValue {
+    fn from(value: &str) -> Self {
+        VarValue::Plain(value.to_string())
+    }
+}
+
+impl From<SecretVar> for VarValue {
+    fn from(secret: SecretVar) -> Self {
+        VarValue::Secret(secret)
+    }
+}
+
+impl PartialEq<str> for VarValue {
+    fn eq(&self, other: &str) -> bool {
+        self.expose() == other
+    }
+}
+
+impl PartialEq<&str> for VarValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.expose() == *other
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Builder)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ValidateOptions {
+    #[builder(default)]
+    #[cfg_attr(feature = "serde", serde(with = "vars_map"))]
+    pub vars: Vec<(String, String)>,
+    #[builder(default)]
+    pub var_files: Vec<PathBuf>,
+    /// Only check syntax, without resolving plugins
+    #[builder(default)]
+    pub syntax_only: bool,
+    /// Warn about variables that are set but not declared in the template
+    #[builder(default)]
+    pub warn_on_undeclared_var: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Builder)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct InitOptions {
+    /// Update installed plugins to the latest version matching the constraints
+    #[builder(default)]
+    pub upgrade: bool,
+    /// Reinstall plugins even if they are already installed
+    #[builder(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Builder)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FmtOptions {
+    /// Don't modify files; fail if any of them would be changed
+    #[builder(default)]
+    pub check: bool,
+    /// Print a diff of the formatting changes
+    #[builder(default)]
+    pub diff: bool,
+    /// Also format templates in subdirectories
+    #[builder(default)]
+    pub recursive: bool,
+}
+
+/// Outcome of a successful `packer fmt` run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FmtResult {
+    /// Files packer reformatted; filled in whether or not [`FmtOptions::diff`] is set
+    pub changed_files: Vec<PathBuf>,
+    /// Unified diff of the changes when [`FmtOptions::diff`] was set, `None` otherwise
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Default, Builder)]
+pub struct Hcl2UpgradeOptions {
+    /// Write the converted template to this file
+    #[builder(default)]
+    pub output_file: Option<PathBuf>,
+    /// Add comments explaining the converted blocks
     #[builder(default)]
-    pub color: bool,
+    pub with_annotations: bool,
+}
+
+/// Options for [`Packer::console`] and [`Packer::console_eval`]
+#[derive(Debug, Clone, PartialEq, Builder)]
+pub struct ConsoleOptions {
     #[builder(default)]
     pub vars: Vec<(String, String)>,
     #[builder(default)]
     pub var_files: Vec<PathBuf>,
+    /// Kill the console if it has not finished after this long; only used by `console_eval`
+    #[builder(default = "Duration::from_secs(30)")]
+    pub timeout: Duration,
 }
 
-impl Default for BuildOptions {
+impl ConsoleOptions {
+    /// Seed the console with these template variables
+    pub fn with_vars<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.vars.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Load variables from these files before starting the console
+    pub fn with_var_files<P: Into<PathBuf>>(mut self, files: impl IntoIterator<Item = P>) -> Self {
+        self.var_files.extend(files.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl Default for ConsoleOptions {
     fn default() -> Self {
-        BuildOptions {
-            parallel_builds: None,
-            debug: false,
-            force: false,
-            timestamp_ui: false,
-            color: true,
+        ConsoleOptions {
             vars: Vec::new(),
             var_files: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Outcome of a successful `packer validate` run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationResult {
+    /// Warnings packer emitted while validating, e.g. `Undefined -var variable`
+    pub warnings: Vec<String>,
+    /// Variables that were passed in but aren't declared by the template
+    ///
+    /// Packer reports these as `Undefined -var variable` or `Undefined
+    /// variable` warnings; they are also in `warnings`. Unless
+    /// [`ValidateOptions::warn_on_undeclared_var`] is set, packer only
+    /// reports variables set with `-var`, not ones from var files.
+    pub undeclared_vars: Vec<String>,
+}
+
+/// Outcome of [`Packer::build_dry_run`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryRunResult {
+    pub validation: ValidationResult,
+    /// The template as packer sees it with the build's variables applied
+    pub inspection: TemplateInspection,
+    /// Plugins the template requires that aren't installed yet
+    pub missing_plugins: Vec<PluginRequirement>,
+}
+
+/// What a batch of builds does when one of them fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartialFailurePolicy {
+    /// Stop at the first failure and return its error
+    #[default]
+    StopOnFirst,
+    /// Run every build and return all failures together
+    CollectAll,
+}
+
+/// Failure handling mode passed to `packer build -on-error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum OnError {
+    /// Abort the build, leaving created resources in place
+    Abort,
+    /// Prompt for what to do.
+    ///
+    /// Only useful when stdin is connected to a terminal; in a non-interactive
+    /// CI process packer will wait for an answer that never comes.
+    Ask,
+    /// Run the `error-cleanup-provisioner`, then abort
+    RunCleanup,
+    /// Clean up and exit (packer's default behaviour)
+    Cleanup,
+}
+
+impl OnError {
+    /// Value passed to the `-on-error` flag
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnError::Abort => "abort",
+            OnError::Ask => "ask",
+            OnError::RunCleanup => "run-cleanup-provisioner",
+            OnError::Cleanup => "cleanup",
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl BuildOptions {
+    /// Load build options from a `.json` or `.toml` file
+    ///
+    /// Parse errors are reported as [`PackerError::ConfigError`] carrying the
+    /// file name and the line and column of the problem.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<BuildOptions> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        match ConfigFormat::from_path(path)? {
+            ConfigFormat::Json => serde_json::from_str(&contents).map_err(|err| {
+                config_parse_error(path, err.line(), err.column(), &err.to_string())
+            }),
+            ConfigFormat::Toml => parse_toml(path, &contents),
+        }
+    }
+
+    /// Save build options to a `.json` or `.toml` file
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        let contents = match ConfigFormat::from_path(path)? {
+            ConfigFormat::Json => serde_json::to_string_pretty(self)?,
+            ConfigFormat::Toml => toml::to_string_pretty(self)?,
+        };
+
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// File formats options can be loaded from, picked by file extension
+#[cfg(feature = "serde")]
+enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+#[cfg(feature = "serde")]
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            _ => Err(PackerError::ConfigError(format!(
+                "{}: unsupported config format, expected a .json or .toml file",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Deserialize TOML read from `path`, reporting errors with their line and column
+#[cfg(feature = "serde")]
+fn parse_toml<T: serde::de::DeserializeOwned>(path: &Path, contents: &str) -> Result<T> {
+    toml::from_str(contents).map_err(|err| {
+        let (line, column) = err
+            .span()
+            .map(|span| line_and_column(contents, span.start))
+            .unwrap_or((0, 0));
+        config_parse_error(path, line, column, err.message())
+    })
+}
+
+#[cfg(feature = "serde")]
+fn config_parse_error(path: &Path, line: usize, column: usize, message: &str) -> PackerError {
+    PackerError::ConfigError(format!(
+        "{}:{}:{}: {}",
+        path.display(),
+        line,
+        column,
+        message
+    ))
+}
+
+/// 1-based line and column of a byte offset into `contents`
+#[cfg(feature = "serde")]
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|index| index + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+impl BuildOptions {
+    /// Add a single template variable
+    pub fn add_var(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.vars.push((key.into(), VarValue::Plain(value.into())));
+        self
+    }
+
+    /// Add several template variables at once
+    pub fn add_vars(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> &mut Self {
+        self.vars.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key, VarValue::Plain(value))),
+        );
+        self
+    }
+
+    /// Options that skip the named builds, e.g. `["amazon-ebs.base"]`
+    ///
+    /// [`Packer::build_except`] as options: everything else keeps its default,
+    /// and the result can be chained, as in
+    /// `BuildOptions::excluding(["amazon-ebs.base"]).with_vars([("env", "dev")])`.
+    pub fn excluding<S: Into<String>>(except: impl IntoIterator<Item = S>) -> BuildOptions {
+        BuildOptions {
+            except: except.into_iter().map(Into::into).collect(),
+            ..BuildOptions::default()
+        }
+    }
+
+    /// These options with the given template variables added
+    pub fn with_vars<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.vars.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), VarValue::Plain(value.into()))),
+        );
+        self
+    }
+
+    /// Check the options for mistakes packer would only report after starting
+    ///
+    /// Fails with [`PackerError::ConfigError`] if a variable has an empty
+    /// name or a var file doesn't exist. Relative var files are looked up in
+    /// the current directory; [`Packer::build`] and every other build method
+    /// run the same checks against the working directory before starting
+    /// packer. `parallel_builds` needs no check: it can't be negative, and
+    /// `0` means no limit.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_in(None)
+    }
+
+    /// [`BuildOptions::validate`], resolving relative var files against `dir`
+    fn validate_in(&self, dir: Option<&Path>) -> Result<()> {
+        if let Some((key, _)) = self.vars.iter().find(|(key, _)| key.trim().is_empty()) {
+            return Err(PackerError::ConfigError(format!(
+                "variable name {:?} is empty",
+                key
+            )));
+        }
+        for var_file in &self.var_files {
+            let path = match dir {
+                Some(dir) if var_file.is_relative() => dir.join(var_file),
+                _ => var_file.clone(),
+            };
+            if !path.is_file() {
+                return Err(PackerError::ConfigError(format!(
+                    "var file {} does not exist",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a variable file
+    pub fn add_var_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.var_files.push(path.into());
+        self
+    }
+
+    /// Options carrying every `PKR_VAR_<name>` variable from the environment
+    ///
+    /// The prefix is stripped, so `PKR_VAR_region=us-west-2` becomes the
+    /// variable `region`. Variables are sorted by name; all other options keep
+    /// their defaults.
+    pub fn from_env() -> BuildOptions {
+        let mut vars: Vec<(String, VarValue)> = std::env::vars()
+            .filter_map(|(key, value)| {
+                key.strip_prefix("PKR_VAR_")
+                    .filter(|name| !name.is_empty())
+                    .map(|name| (name.to_string(), VarValue::Plain(value)))
+            })
+            .collect();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+
+        BuildOptions {
+            vars,
+            ..BuildOptions::default()
+        }
+    }
+
+    /// Layer `other` on top of these options
+    ///
+    /// - `debug`, `force`, `timestamp_ui`, `disable_checkpoint` and
+    ///   `isolated_env` are taken from `other`.
+    /// - `parallel_builds`, `color`, `on_error` and `machine_readable_log_file`
+    ///   are taken from `other` when set there, otherwise kept from `self`.
+    /// - `vars`, `var_files`, `only`, `except` and `env_whitelist` are
+    ///   concatenated, `self` first.
+    ///   Note that this combines the `-only`/`-except` lists of both sources;
+    ///   for duplicate variables packer uses the last value, so `other` wins.
+    pub fn merge(mut self, other: BuildOptions) -> BuildOptions {
+        self.parallel_builds = other.parallel_builds.or(self.parallel_builds);
+        self.debug = other.debug;
+        self.force = other.force;
+        self.timestamp_ui = other.timestamp_ui;
+        self.color = other.color.or(self.color);
+        self.disable_checkpoint = other.disable_checkpoint;
+        self.isolated_env = other.isolated_env;
+        self.env_whitelist.extend(other.env_whitelist);
+        self.vars.extend(other.vars);
+        self.var_files.extend(other.var_files);
+        self.only.extend(other.only);
+        self.except.extend(other.except);
+        self.on_error = other.on_error.or(self.on_error);
+        self.machine_readable_log_file = other
+            .machine_readable_log_file
+            .or(self.machine_readable_log_file);
+        self
+    }
+
+    /// Add a variable whose value is redacted when the options are printed
+    pub fn add_secret_var(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> &mut Self {
+        self.vars
+            .push((key.into(), VarValue::Secret(SecretVar::new(value))));
+        self
+    }
+
+    /// A copy of these options with every secret value replaced by `[REDACTED]`
+    ///
+    /// `clone()` keeps the real values and is what to hand to packer. This
+    /// one is for observability: the variables stay in place, so the copy can
+    /// be logged or serialized without leaking anything. Builds log their
+    /// options this way when the `tracing` or `logging` feature is on.
+    pub fn redacted_clone(&self) -> BuildOptions {
+        let mut redacted = self.clone();
+        for (_, value) in &mut redacted.vars {
+            if let VarValue::Secret(secret) = value {
+                *secret = SecretVar::new("[REDACTED]");
+            }
+        }
+        redacted
+    }
+
+    /// The `packer build` flags these options turn into, as passed by [`Packer::build`]
+    ///
+    /// Secret variables appear with their real values; the `Display` impl
+    /// shows the same flags with secrets redacted.
+    pub fn to_args(&self) -> Vec<OsString> {
+        self.args(|value| value.expose().to_string())
+    }
+
+    fn args(&self, var_value: impl Fn(&VarValue) -> String) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+
+        if self.debug {
+            args.push("-debug".into());
+        }
+        if self.force {
+            args.push("-force".into());
+        }
+        if let Some(parallel) = self.parallel_builds {
+            args.push("-parallel-builds".into());
+            args.push(parallel.to_string().into());
+        }
+        if self.color == Some(false) {
+            args.push("-color=false".into());
+        }
+        if self.timestamp_ui {
+            args.push("-timestamp-ui".into());
+        }
+        if !self.only.is_empty() {
+            args.push(format!("-only={}", self.only.join(",")).into());
+        }
+        if !self.except.is_empty() {
+            args.push(format!("-except={}", self.except.join(",")).into());
+        }
+        if let Some(on_error) = self.on_error {
+            args.push(format!("-on-error={}", on_error.as_str()).into());
+        }
+
+        for (key, value) in &self.vars {
+            args.push("-var".into());
+            args.push(format!("{}={}", key, var_value(value)).into());
+        }
+        for var_file in &self.var_files {
+            let mut arg = OsString::from("-var-file=");
+            arg.push(var_file);
+            args.push(arg);
+        }
+
+        args
+    }
+}
+
+/// Renders the options as `packer build` flags, quoted so they can be pasted into a shell
+///
+/// Secret variable values are shown as `***`.
+impl std::fmt::Display for BuildOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args = self.args(|value| value.to_string());
+        for (index, arg) in args.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(&shell_quote(&arg.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            parallel_builds: None,
+            debug: false,
+            force: false,
+            timestamp_ui: false,
+            color: None,
+            vars: Vec::new(),
+            var_files: Vec::new(),
+            only: Vec::new(),
+            except: Vec::new(),
+            on_error: None,
+            machine_readable_log_file: None,
+            disable_checkpoint: true,
+            isolated_env: false,
+            env_whitelist: Vec::new(),
+        }
+    }
+}
+
+/// Outcome of a successful `packer build` run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildResult {
+    pub artifacts: Vec<BuildArtifact>,
+    /// Wall-clock time the build took
+    pub duration: std::time::Duration,
+    /// Time each builder took, keyed by builder name
+    ///
+    /// Only filled in from machine-readable output, which [`Packer::build`]
+    /// always requests. A builder's `timing` event is used when packer emits
+    /// one; otherwise the time is the span between the builder's first and
+    /// last event, with packer's one-second timestamp resolution.
+    pub build_times: HashMap<String, Duration>,
+    /// Everything packer wrote to stderr, including provisioner output
+    ///
+    /// When a build fails the same text is in the `stderr` field of
+    /// [`PackerError::ExecutionError`].
+    pub provisioner_log: String,
+}
+
+impl BuildResult {
+    /// The builder that took the longest, if any timing data is available
+    pub fn slowest_builder(&self) -> Option<(&str, Duration)> {
+        self.build_times
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, duration)| (name.as_str(), *duration))
+    }
+}
+
+/// An artifact produced by a build, e.g. an AMI or a Docker image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildArtifact {
+    /// Name of the build that produced the artifact, e.g. `amazon-ebs.base`
+    pub builder_name: String,
+    /// Builder ID, e.g. `mitchellh.amazonebs`
+    pub artifact_type: String,
+    /// Artifact ID, e.g. `us-east-1:ami-0123456789abcdef0`
+    pub artifact_id: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// A single line of `packer -machine-readable` output
+///
+/// Lines have the form `timestamp,target,type,data...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineReadableEvent {
+    /// Unix timestamp the line was emitted at
+    pub timestamp: u64,
+    /// Build the line relates to; empty for global messages
+    pub target: String,
+    pub event_type: String,
+    /// Remaining fields, with packer's comma and newline escapes undone
+    pub data: Vec<String>,
+}
+
+/// The common machine-readable event types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineReadableEventKind {
+    Artifact,
+    ArtifactCount,
+    Ui,
+    Error,
+    Version,
+    Other(String),
+}
+
+impl MachineReadableEvent {
+    pub fn kind(&self) -> MachineReadableEventKind {
+        match self.event_type.as_str() {
+            "artifact" => MachineReadableEventKind::Artifact,
+            "artifact-count" => MachineReadableEventKind::ArtifactCount,
+            "ui" => MachineReadableEventKind::Ui,
+            "error" => MachineReadableEventKind::Error,
+            "version" => MachineReadableEventKind::Version,
+            other => MachineReadableEventKind::Other(other.to_string()),
+        }
+    }
+}
+
+/// Parse `packer -machine-readable` output into events
+///
+/// Lines that don't follow the machine-readable format are skipped.
+pub fn parse_machine_readable(output: &str) -> Vec<MachineReadableEvent> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim_end_matches('\r').split(',');
+            let timestamp = fields.next()?.parse().ok()?;
+            let target = fields.next()?.to_string();
+            let event_type = fields.next()?.to_string();
+            let data = fields
+                .map(|field| {
+                    field
+                        .replace("%!(PACKER_COMMA)", ",")
+                        .replace("\\n", "\n")
+                        .replace("\\r", "\r")
+                })
+                .collect();
+
+            Some(MachineReadableEvent {
+                timestamp,
+                target,
+                event_type,
+                data,
+            })
+        })
+        .collect()
+}
+
+/// A line of output from a running Packer command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+    /// How packer exited; the last item of `Packer::build_stream`
+    ExitStatus(std::process::ExitStatus),
+}
+
+/// Receives progress events while [`Packer::build_with_sink`] runs
+///
+/// Every method has an empty default, so a sink only implements the events
+/// it cares about.
+pub trait BuildEventSink {
+    /// A builder emitted its first event
+    fn on_start(&mut self, _builder: &str) {}
+    /// A builder started provisioner number `step` (counted per builder, from 1)
+    fn on_provisioner(&mut self, _step: u32, _description: &str) {}
+    /// A builder finished reporting an artifact
+    fn on_artifact(&mut self, _artifact: &BuildArtifact) {}
+    /// A builder finished without reporting an error
+    fn on_complete(&mut self, _builder: &str, _duration: Duration) {}
+    /// A builder reported an error
+    fn on_error(&mut self, _builder: &str, _message: &str) {}
+}
+
+/// A [`BuildEventSink`] that prints one line per event to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoggingEventSink;
+
+impl BuildEventSink for LoggingEventSink {
+    fn on_start(&mut self, builder: &str) {
+        eprintln!("{}: started", builder);
+    }
+
+    fn on_provisioner(&mut self, step: u32, description: &str) {
+        eprintln!("step {}: {}", step, description);
+    }
+
+    fn on_artifact(&mut self, artifact: &BuildArtifact) {
+        eprintln!(
+            "{}: produced {}",
+            artifact.builder_name, artifact.artifact_id
+        );
+    }
+
+    fn on_complete(&mut self, builder: &str, duration: Duration) {
+        eprintln!("{}: finished after {:?}", builder, duration);
+    }
+
+    fn on_error(&mut self, builder: &str, message: &str) {
+        eprintln!("{}: error: {}", builder, message);
+    }
+}
+
+/// Turns machine-readable build output, line by line, into [`BuildEventSink`] calls
+#[derive(Debug, Default)]
+struct BuildEventTracker {
+    /// Builders in the order they emitted their first event
+    started: Vec<String>,
+    failed: Vec<String>,
+    provisioner_steps: HashMap<String, u32>,
+    /// Artifact lines seen so far, keyed by builder and artifact index
+    artifacts: HashMap<(String, String), String>,
+    output: String,
+}
+
+impl BuildEventTracker {
+    fn feed<F: BuildEventSink + ?Sized>(&mut self, line: &str, sink: &mut F) {
+        self.output.push_str(line);
+        self.output.push('\n');
+
+        for event in parse_machine_readable(line) {
+            if event.target.is_empty() {
+                continue;
+            }
+            if !self.started.contains(&event.target) {
+                self.started.push(event.target.clone());
+                sink.on_start(&event.target);
+            }
+
+            match event.kind() {
+                MachineReadableEventKind::Ui => {
+                    let message = event.data.get(1).map_or("", |message| message.trim());
+                    if message.contains("Provisioning with") {
+                        let step = self
+                            .provisioner_steps
+                            .entry(event.target.clone())
+                            .or_default();
+                        *step += 1;
+                        sink.on_provisioner(*step, message);
+                    }
+                }
+                MachineReadableEventKind::Error => {
+                    let message = event.data.first().map_or("", |message| message.trim());
+                    if !self.failed.contains(&event.target) {
+                        self.failed.push(event.target.clone());
+                    }
+                    sink.on_error(&event.target, message);
+                }
+                MachineReadableEventKind::Artifact if event.data.len() >= 2 => {
+                    let key = (event.target.clone(), event.data[0].clone());
+                    let lines = self.artifacts.entry(key.clone()).or_default();
+                    lines.push_str(line);
+                    lines.push('\n');
+
+                    if event.data[1] == "end" {
+                        let lines = self.artifacts.remove(&key).unwrap_or_default();
+                        for artifact in parse_artifacts(&lines) {
+                            sink.on_artifact(&artifact);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Report every builder that started and didn't fail as complete
+    fn finish<F: BuildEventSink + ?Sized>(self, sink: &mut F) {
+        let times = parse_build_times(&self.output);
+        for builder in &self.started {
+            if !self.failed.contains(builder) {
+                sink.on_complete(builder, times.get(builder).copied().unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// A plugin installed by [`Packer::init`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginInstallRecord {
+    /// Plugin source, e.g. `github.com/hashicorp/amazon`
+    pub source: String,
+    /// Version without the leading `v`, e.g. `1.2.1`
+    pub version: String,
+    /// Path to the installed plugin binary
+    pub path: PathBuf,
+}
+
+/// An installed Packer plugin
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginInfo {
+    /// Plugin source, e.g. `github.com/hashicorp/amazon`
+    pub name: String,
+    /// Version without the leading `v`, e.g. `1.2.1`
+    pub version: String,
+    /// Path to the plugin binary, when packer reports it
+    pub path: PathBuf,
+}
+
+/// Components of a template, as reported by `packer inspect`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateInspection {
+    /// Format of the inspected template; `None` if it could not be told
+    pub kind: Option<TemplateKind>,
+    pub builders: Vec<BuilderInfo>,
+    pub provisioners: Vec<ProvisionerInfo>,
+    pub post_processors: Vec<PostProcessorInfo>,
+}
+
+/// Format of a packer template, see [`detect_template_kind`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TemplateKind {
+    /// An HCL2 template (`.pkr.hcl`, `.pkr.json`, or a directory of them)
+    Hcl2,
+    /// A legacy JSON template with a top-level `builders` list
+    LegacyJson,
+}
+
+/// A packer template, optionally with its format already known
+///
+/// Every method that takes a template, such as [`Packer::build`] and
+/// [`Packer::validate`], takes anything that converts into one, so paths
+/// and string literals keep working. Setting [`Template::kind`] up front,
+/// e.g. with [`Template::detect`], skips detecting the format on every call.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Template {
+    /// Template file or directory; relative paths are resolved against the working directory
+    pub path: PathBuf,
+    /// Format of the template; detected from the file when `None`
+    pub kind: Option<TemplateKind>,
+}
+
+impl Template {
+    /// A template whose format is detected when it is needed
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            kind: None,
+        }
+    }
+
+    /// A template with its format detected now, see [`detect_template_kind`]
+    pub fn detect(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let kind = detect_template_kind(&path)?;
+        Ok(Self::new(path).with_kind(kind))
+    }
+
+    /// Record the template's format, so it is not detected again
+    pub fn with_kind(mut self, kind: TemplateKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// The known format, or the one detected at `resolved`, the template's actual location
+    fn kind_at(&self, resolved: &Path) -> Option<TemplateKind> {
+        self.kind.or_else(|| detect_template_kind(resolved).ok())
+    }
+}
+
+impl AsRef<Path> for Template {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl From<PathBuf> for Template {
+    fn from(path: PathBuf) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<&PathBuf> for Template {
+    fn from(path: &PathBuf) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<&Path> for Template {
+    fn from(path: &Path) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<&str> for Template {
+    fn from(path: &str) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<String> for Template {
+    fn from(path: String) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<&String> for Template {
+    fn from(path: &String) -> Self {
+        Template::new(path)
+    }
+}
+
+impl From<&Template> for Template {
+    fn from(template: &Template) -> Self {
+        template.clone()
+    }
+}
+
+/// A builder (HCL2 source) in an inspected template
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuilderInfo {
+    /// Builder type, e.g. `amazon-ebs`
+    pub type_name: String,
+    /// Builder name; the same as `type_name` when the template doesn't name it
+    pub name: String,
+}
+
+/// A provisioner in an inspected template
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProvisionerInfo {
+    /// Provisioner type, e.g. `shell`
+    pub type_name: String,
+    /// Provisioner name; the same as `type_name` when the template doesn't name it
+    pub name: String,
+}
+
+/// A post-processor in an inspected template
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PostProcessorInfo {
+    /// Post-processor type, e.g. `manifest`
+    pub type_name: String,
+    /// Post-processor name; the same as `type_name` when the template doesn't name it
+    pub name: String,
+}
+
+/// A plugin required by a template's `required_plugins` block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginRequirement {
+    /// Plugin source, e.g. `github.com/hashicorp/amazon`
+    pub source: String,
+    /// Version constraint, e.g. `>= 1.2.0`; empty when unconstrained
+    pub version_constraint: String,
+}
+
+/// A plugin to install, optionally pinned to a version
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginRef {
+    /// Plugin source, e.g. `github.com/hashicorp/amazon`
+    pub source: String,
+    /// Exact version to install, e.g. `v1.2.1`; the latest when `None`
+    pub version: Option<String>,
+}
+
+impl PluginRef {
+    pub fn new(source: impl Into<String>) -> Self {
+        PluginRef {
+            source: source.into(),
+            version: None,
+        }
+    }
+
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+}
+
+impl From<&str> for PluginRef {
+    fn from(source: &str) -> Self {
+        PluginRef::new(source)
+    }
+}
+
+impl From<String> for PluginRef {
+    fn from(source: String) -> Self {
+        PluginRef::new(source)
+    }
+}
+
+impl From<&String> for PluginRef {
+    fn from(source: &String) -> Self {
+        PluginRef::new(source.as_str())
+    }
+}
+
+/// Captured output of a finished Packer command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code, or `-1` if the process was terminated by a signal
+    pub exit_code: i32,
+}
+
+impl From<std::process::Output> for CommandOutput {
+    fn from(output: std::process::Output) -> Self {
+        Self {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+        }
+    }
+}
+
+/// Instance-level settings for a [`Packer`], see [`Packer::with_config`]
+///
+/// Every field is optional; the defaults give the same instance as
+/// [`Packer::new`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PackerConfig {
+    /// Packer binary to use; looked up like [`Packer::new`] does when unset
+    pub executable_path: Option<PathBuf>,
+    pub working_dir: Option<PathBuf>,
+    /// Environment variables passed to packer
+    #[cfg_attr(feature = "serde", serde(with = "vars_map"))]
+    pub env_vars: Vec<(String, String)>,
+    /// See [`Packer::with_packer_log`]
+    pub log_level: Option<u8>,
+    /// See [`Packer::with_log_path`]
+    pub log_path: Option<PathBuf>,
+    /// See [`Packer::with_cache_dir`]
+    pub cache_dir: Option<PathBuf>,
+    /// See [`Packer::with_tmp_dir`]
+    pub tmp_dir: Option<PathBuf>,
+    /// Template variables passed to every command as `PKR_VAR_<name>`
+    #[cfg_attr(feature = "serde", serde(with = "vars_map"))]
+    pub default_vars: Vec<(String, String)>,
+    /// See [`Packer::with_health_check_timeout`]; whole seconds
+    pub health_check_timeout_secs: Option<u64>,
+}
+
+impl PackerConfig {
+    /// An annotated TOML file showing every setting, for documentation and templates
+    pub fn example_toml() -> String {
+        r#"# Packer binary to use; looked up in the current directory and on PATH when unset
+executable_path = "/usr/local/bin/packer"
+# Directory packer commands run in
+working_dir = "./templates"
+# PACKER_LOG level; 0 turns packer's debug log off
+log_level = 1
+# File packer's debug log is written to
+log_path = "packer.log"
+# PACKER_CACHE_DIR and PACKER_TMP_DIR; both must be existing directories
+cache_dir = ".packer.d/cache"
+tmp_dir = ".packer.d/tmp"
+# Seconds Packer::check_health waits for `packer version` (default 5)
+health_check_timeout_secs = 5
+
+# Extra environment variables for packer
+[env_vars]
+AWS_PROFILE = "ci"
+
+# Template variables, passed to every command as PKR_VAR_<name>
+[default_vars]
+region = "us-west-2"
+"#
+        .to_string()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl PackerConfig {
+    /// Load settings from a TOML file, see [`PackerConfig::example_toml`] for the format
+    ///
+    /// Parse errors are reported as [`PackerError::ConfigError`] carrying the
+    /// file name and the line and column of the problem.
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<PackerConfig> {
+        let path = path.as_ref();
+        parse_toml(path, &std::fs::read_to_string(path)?)
+    }
+
+    /// Load settings from the TOML file named by `PACKER_CONFIG`
+    ///
+    /// Returns the default settings if the variable is not set.
+    pub fn from_env() -> Result<PackerConfig> {
+        match std::env::var_os("PACKER_CONFIG") {
+            Some(path) if !path.is_empty() => Self::from_toml_file(path),
+            _ => Ok(PackerConfig::default()),
+        }
+    }
+}
+
+/// Fluent constructor for [`Packer`] that validates all settings in [`PackerBuilder::build`]
+#[derive(Debug, Clone, Default)]
+pub struct PackerBuilder {
+    config: PackerConfig,
+}
+
+impl PackerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the packer binary at `path` instead of looking one up
+    pub fn executable(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.executable_path = Some(path.into());
+        self
+    }
+
+    pub fn working_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.working_dir = Some(dir.into());
+        self
+    }
+
+    pub fn env_var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config.env_vars.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn env_vars<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config.env_vars.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// See [`Packer::with_packer_log`]
+    pub fn log_level(mut self, level: u8) -> Self {
+        self.config.log_level = Some(level);
+        self
+    }
+
+    /// See [`Packer::with_log_path`]
+    pub fn log_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.log_path = Some(path.into());
+        self
+    }
+
+    /// See [`Packer::with_cache_dir`]
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// See [`Packer::with_tmp_dir`]
+    pub fn tmp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.tmp_dir = Some(dir.into());
+        self
+    }
+
+    /// Validate the settings and construct the [`Packer`]
+    ///
+    /// Without an explicit [`PackerBuilder::executable`] the binary is located
+    /// the same way as [`Packer::new`] does.
+    pub fn build(self) -> Result<Packer> {
+        Packer::with_config(self.config)
+    }
+}
+
+/// A parsed Packer version, e.g. `1.10.3` or `1.11.0-dev`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PackerVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Pre-release tag, e.g. `dev` or `beta1`
+    pub pre: Option<String>,
+}
+
+impl PackerVersion {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: None,
+        }
+    }
+}
+
+impl std::fmt::Display for PackerVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for PackerVersion {
+    type Err = PackerError;
+
+    /// Parse either a bare version (`1.10.3`) or the first line of
+    /// `packer version` output (`Packer v1.10.3`)
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || PackerError::ConfigError(format!("unrecognised Packer version: {:?}", s));
+
+        let first_line = s.lines().next().unwrap_or("").trim();
+        let version = first_line
+            .strip_prefix("Packer")
+            .unwrap_or(first_line)
+            .trim()
+            .trim_start_matches('v');
+
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, pre)) => (numbers, Some(pre.to_string())),
+            None => (version, None),
+        };
+
+        let mut parts = numbers.split('.').map(|part| part.parse::<u64>());
+        let mut next = || parts.next().ok_or_else(invalid)?.map_err(|_| invalid());
+        let major = next()?;
+        let minor = next()?;
+        let patch = next()?;
+
+        Ok(Self {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl PartialOrd for PackerVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PackerVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                // A pre-release sorts before the release it leads up to
+                (None, None) => std::cmp::Ordering::Equal,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+impl PackerVersion {
+    /// Whether this packer has `feature`
+    ///
+    /// Pre-releases of the version a feature shipped in don't count.
+    pub fn supports(&self, feature: PackerFeature) -> bool {
+        *self >= feature.min_version()
+    }
+}
+
+/// A packer command that only exists from some release on
+///
+/// Methods wrapping these commands check the installed version first and
+/// fail with [`PackerError::VersionError`] instead of running a packer that
+/// doesn't know the command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PackerFeature {
+    /// `packer fmt`, see [`Packer::fmt`]
+    Fmt,
+    /// `packer hcl2_upgrade`, see [`Packer::hcl2_upgrade`]
+    Hcl2Upgrade,
+    /// `packer plugins required`, see [`PluginManager::required`]
+    PluginsRequired,
+}
+
+impl PackerFeature {
+    /// The first packer release with this feature
+    pub fn min_version(self) -> PackerVersion {
+        match self {
+            // `fmt` shipped with HCL2 support in 1.6.0; requiring anything newer
+            // would reject the 1.7.8 release `Packer::new` installs.
+            PackerFeature::Fmt => PackerVersion::new(1, 6, 0),
+            PackerFeature::Hcl2Upgrade => PackerVersion::new(1, 6, 5),
+            PackerFeature::PluginsRequired => PackerVersion::new(1, 8, 4),
+        }
+    }
+}
+
+impl Packer {
+    /// Create a new Packer instance
+    ///
+    /// Uses the release a previous call installed into the cache directory if
+    /// there is one, then looks for `./packer` (or `./packer.exe` on Windows),
+    /// then falls back to searching the directories listed in `PATH`. If none
+    /// of them turns up a binary, Packer is downloaded into the cache directory.
+    pub fn new() -> Result<Self> {
+        Self::with_config(PackerConfig::default())
+    }
+
+    /// Create a Packer instance from a [`PackerConfig`], validating every setting
+    ///
+    /// Fails with [`PackerError::NotFound`] if the configured executable does
+    /// not exist and with [`PackerError::ConfigError`] if it is not executable
+    /// or a configured directory does not exist.
+    pub fn with_config(config: PackerConfig) -> Result<Self> {
+        let mut packer = match config.executable_path {
+            Some(executable) => {
+                if !executable.exists() {
+                    return Err(PackerError::not_found(vec![executable]));
+                }
+                if !is_executable(&executable) {
+                    return Err(PackerError::ConfigError(format!(
+                        "{} is not an executable file",
+                        executable.display()
+                    )));
+                }
+                Self::new_with_path(executable)?
+            }
+            None => match Self::find_executable() {
+                Ok(executable) => Self::new_with_path(executable)?,
+                Err(PackerError::NotFound(not_found)) => match install_packer() {
+                    Ok(installed) => Self::new_with_path(installed)?,
+                    // Offline; report where we looked rather than the install attempt
+                    Err(PackerError::NotFound(_)) => return Err(PackerError::NotFound(not_found)),
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(e),
+            },
+        };
+
+        if let Some(dir) = config.working_dir {
+            packer = packer.with_working_dir(dir);
+        }
+        packer = packer.with_envs(config.env_vars);
+        if let Some(level) = config.log_level {
+            packer = packer.with_packer_log(level);
+        }
+        if let Some(path) = config.log_path {
+            packer = packer.with_log_path(path);
+        }
+        if let Some(dir) = config.cache_dir {
+            packer = packer.with_cache_dir(dir)?;
+        }
+        if let Some(dir) = config.tmp_dir {
+            packer = packer.with_tmp_dir(dir)?;
+        }
+        for (name, value) in config.default_vars {
+            packer = packer.with_env(&format!("PKR_VAR_{}", name), &value);
+        }
+        if let Some(secs) = config.health_check_timeout_secs {
+            packer = packer.with_health_check_timeout(Duration::from_secs(secs));
+        }
+
+        Ok(packer)
+    }
+
+    /// Locate a packer binary without downloading one
+    ///
+    /// Checks for the release [`Packer::new`] installs into the cache
+    /// directory, then `./packer` (or `./packer.exe` on Windows), then searches
+    /// `PATH`, honouring `PATHEXT` on Windows. Returns
+    /// [`PackerError::NotFound`], listing every place checked, if none has one.
+    ///
+    /// A `./packer` that exists but isn't executable (for example after
+    /// copying it from a `noexec` volume) is a [`PackerError::ConfigError`]
+    /// suggesting `chmod +x`, rather than a reason to look elsewhere or
+    /// download it again.
+    pub fn find_executable() -> Result<PathBuf> {
+        let mut searched = Vec::new();
+        if let Ok((version, _)) = packer_release() {
+            let installed = installed_executable(&version);
+            if is_executable(&installed) {
+                return Ok(installed);
+            }
+            searched.push(installed);
+        }
+
+        let local = local_executable();
+        if local.is_file() {
+            if !is_executable(&local) {
+                return Err(PackerError::ConfigError(format!(
+                    "{} exists but is not executable; run `chmod +x {}` instead of downloading it again",
+                    local.display(),
+                    local.display()
+                )));
+            }
+            return Ok(local);
+        }
+        searched.push(local);
+
+        which::which("packer").map_err(|_| {
+            let name = if cfg!(target_os = "windows") {
+                "packer.exe"
+            } else {
+                "packer"
+            };
+            let path = std::env::var_os("PATH").unwrap_or_default();
+            searched.extend(std::env::split_paths(&path).map(|dir| dir.join(name)));
+            PackerError::not_found(searched)
+        })
+    }
+
+    /// Create a Packer instance using the binary at `path`
+    pub fn new_with_path(path: impl Into<PathBuf>) -> Result<Self> {
+        let executable = path.into();
+
+        if !executable.exists() {
+            return Err(PackerError::not_found(vec![executable]));
+        }
+
+        Ok(Self {
+            executable,
+            working_dir: None,
+            env_vars: Vec::new(),
+            env_removals: Vec::new(),
+            stdout_writer: None,
+            stderr_writer: None,
+            health_check_timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            stderr_limit: DEFAULT_STDERR_LIMIT,
+            launcher: Arc::new(RealProcessLauncher),
+            inspect_cache: None,
+            version: OnceLock::new(),
+        })
+    }
+
+    /// Create a Packer instance from the `PACKER_BINARY` environment variable
+    ///
+    /// Falls back to [`Packer::new`] when the variable is not set.
+    pub fn from_env() -> Result<Self> {
+        let Some(binary) = std::env::var_os("PACKER_BINARY") else {
+            return Self::new();
+        };
+
+        let executable = PathBuf::from(binary);
+        if !executable.exists() {
+            return Err(PackerError::ConfigError(format!(
+                "PACKER_BINARY points to {}, which does not exist",
+                executable.display()
+            )));
+        }
+        if !is_executable(&executable) {
+            return Err(PackerError::ConfigError(format!(
+                "PACKER_BINARY points to {}, which is not an executable file",
+                executable.display()
+            )));
+        }
+
+        Self::new_with_path(executable)
+    }
+
+    /// Start building a Packer instance, see [`PackerBuilder`]
+    pub fn builder() -> PackerBuilder {
+        PackerBuilder::new()
+    }
+
+    /// Run packer's processes through `launcher` instead of starting them directly
+    ///
+    /// Mainly for tests, see `MockProcessLauncher` (`test-support` feature).
+    pub fn with_launcher(mut self, launcher: impl ProcessLauncher + 'static) -> Self {
+        self.launcher = Arc::new(launcher);
+        self.version = OnceLock::new();
+        self
+    }
+
+    /// Set how long [`Packer::check_health`] waits for packer to answer (default 5 seconds)
+    pub fn with_health_check_timeout(mut self, timeout: Duration) -> Self {
+        self.health_check_timeout = timeout;
+        self
+    }
+
+    /// Set how many bytes of stderr a failed command keeps (default 4096)
+    ///
+    /// Only the end of the output is kept, since that is where packer reports
+    /// what went wrong. This caps the memory held by the
+    /// [`PackerError::ExecutionError`] of a long build that fails late.
+    pub fn with_stderr_limit(mut self, bytes: usize) -> Self {
+        self.stderr_limit = bytes;
+        self
+    }
+
+    /// Set working directory for Packer commands
+    pub fn with_working_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Run Packer commands in a new temporary directory
+    ///
+    /// The directory is deleted when the returned [`tempfile::TempDir`] is
+    /// dropped, so keep it alive for as long as the `Packer` is used: once it
+    /// is gone, every command fails with [`PackerError::IoError`].
+    #[cfg(feature = "tempfile")]
+    pub fn with_temp_working_dir(self) -> Result<(Packer, tempfile::TempDir)> {
+        let dir = tempfile::TempDir::new()?;
+        Ok((self.with_working_dir(dir.path()), dir))
+    }
+
+    /// Remember the results of [`Packer::inspect_parsed`]
+    ///
+    /// A template file is inspected again only once its modification time
+    /// changes; until then the stored [`TemplateInspection`] is returned
+    /// without running packer. Directories of HCL2 files are not cached,
+    /// since editing a file doesn't change the directory's own time. The
+    /// cache is shared by clones of this instance.
+    pub fn with_inspect_cache(mut self) -> Self {
+        self.inspect_cache = Some(Arc::default());
+        self
+    }
+
+    /// Set an environment variable for Packer commands
+    ///
+    /// The variable is only passed to the spawned packer processes; the
+    /// environment of the current process is left untouched.
+    pub fn with_env(mut self, key: &str, value: &str) -> Self {
+        self.env_vars.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set several environment variables for Packer commands
+    pub fn with_envs<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env_vars.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Enable packer's debug log by setting `PACKER_LOG` for Packer commands
+    ///
+    /// A `level` of `0` turns the log off, even if `PACKER_LOG` is set in the
+    /// environment of the current process.
+    pub fn with_packer_log(mut self, level: u8) -> Self {
+        if level == 0 {
+            self.env_vars.retain(|(key, _)| key != "PACKER_LOG");
+            self.env_removals.push("PACKER_LOG".to_string());
+        } else {
+            self.set_env("PACKER_LOG", level.to_string());
+        }
+        self
+    }
+
+    /// Write packer's debug log to `path` by setting `PACKER_LOG_PATH`
+    ///
+    /// The log is only written when it is enabled, see [`Packer::with_packer_log`].
+    pub fn with_log_path(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        self.set_env("PACKER_LOG_PATH", path.display().to_string());
+        self
+    }
+
+    /// Copy packer's stdout to `writer` instead of inheriting the parent's stdout
+    ///
+    /// Output that is captured for parsing is still captured; the writer gets
+    /// a copy of it.
+    pub fn with_stdout_writer(mut self, writer: Box<dyn Write + Send>) -> Self {
+        self.stdout_writer = Some(SharedWriter(Arc::new(Mutex::new(writer))));
+        self
+    }
+
+    /// Copy packer's stderr to `writer` instead of inheriting the parent's stderr
+    pub fn with_stderr_writer(mut self, writer: Box<dyn Write + Send>) -> Self {
+        self.stderr_writer = Some(SharedWriter(Arc::new(Mutex::new(writer))));
+        self
+    }
+
+    /// Store downloaded ISOs and other cached files in `dir` by setting `PACKER_CACHE_DIR`
+    ///
+    /// Fails immediately if `dir` is not an existing directory.
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = existing_dir(dir.into(), "cache")?;
+        self.set_env("PACKER_CACHE_DIR", dir.display().to_string());
+        Ok(self)
+    }
+
+    /// Store intermediate files in `dir` by setting `PACKER_TMP_DIR`
+    ///
+    /// Fails immediately if `dir` is not an existing directory.
+    pub fn with_tmp_dir(mut self, dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = existing_dir(dir.into(), "temporary")?;
+        self.set_env("PACKER_TMP_DIR", dir.display().to_string());
+        Ok(self)
+    }
+
+    /// Resolve `path` the way packer sees it, i.e. relative to the working directory
+    fn resolve_path(&self, path: &Path) -> PathBuf {
+        match &self.working_dir {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Set an environment variable, replacing any earlier value for the same key
+    fn set_env(&mut self, key: &str, value: String) {
+        self.env_vars.retain(|(existing, _)| existing != key);
+        self.env_removals.retain(|existing| existing != key);
+        self.env_vars.push((key.to_string(), value));
+    }
+
+    /// The value packer will see for environment variable `key`
+    fn env_value(&self, key: &str) -> Option<String> {
+        if self.env_removals.iter().any(|removed| removed == key) {
+            return None;
+        }
+        match self
+            .env_vars
+            .iter()
+            .rev()
+            .find(|(existing, _)| existing == key)
+        {
+            Some((_, value)) => Some(value.clone()),
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    /// Whether `PACKER_NO_COLOR` turns color off, see [`BuildOptions::color`]
+    fn env_no_color(&self) -> bool {
+        self.env_value("PACKER_NO_COLOR")
+            .is_some_and(|v| !v.is_empty())
+    }
+
+    /// Build images using a template
+    ///
+    /// Packer is run with `-machine-readable` so the produced artifacts can be
+    /// reported back in the [`BuildResult`]. The options are checked with
+    /// [`BuildOptions::validate`] before packer is started.
+    ///
+    /// On Unix packer runs in its own process group, and while it runs SIGTERM
+    /// and SIGINT sent to this process are forwarded to that group, so packer
+    /// can clean up instead of being orphaned. This replaces any handlers you
+    /// installed for those signals until the build ends; a signal caught in
+    /// the meantime is raised again afterwards. At most 64 packer processes
+    /// can be forwarded to at once; past that a command fails with
+    /// [`PackerError::IoError`] instead of running without forwarding.
+    pub fn build(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+    ) -> Result<BuildResult> {
+        let cmd = self.build_command(&template.into().path, options)?;
+
+        let started = Instant::now();
+        let output = match self.machine_readable_log_file(options) {
+            Some(log_file) => self.execute_command_to_file(cmd, &log_file)?,
+            None => self.execute_command_capture(cmd)?,
+        };
+
+        Ok(build_result(output, started))
+    }
+
+    /// Build only the named builds, e.g. `&["amazon-ebs.my_ami"]`, with default options
+    pub fn build_only(
+        &self,
+        template: impl Into<Template>,
+        targets: &[&str],
+    ) -> Result<BuildResult> {
+        let options = BuildOptions {
+            only: targets.iter().map(|target| target.to_string()).collect(),
+            ..BuildOptions::default()
+        };
+        self.build(template, &options)
+    }
+
+    /// Build everything except the named builds, with default options
+    pub fn build_except(
+        &self,
+        template: impl Into<Template>,
+        targets: &[&str],
+    ) -> Result<BuildResult> {
+        let options = BuildOptions {
+            except: targets.iter().map(|target| target.to_string()).collect(),
+            ..BuildOptions::default()
+        };
+        self.build(template, &options)
+    }
+
+    /// Check a template as far as possible without building anything
+    ///
+    /// Packer has no dry-run mode, so this runs `packer validate` and
+    /// `packer inspect` with the variables and var files of `options`, then
+    /// lists the required plugins that are missing. A template that passes
+    /// can still fail during the build, for example when a cloud API rejects
+    /// something, but most configuration mistakes show up here without
+    /// provisioning a machine. Listing plugins needs packer 1.8.4 or later.
+    pub fn build_dry_run(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+    ) -> Result<DryRunResult> {
+        options.validate_in(self.working_dir.as_deref())?;
+        let template = template.into();
+        let vars: Vec<(String, String)> = options
+            .vars
+            .iter()
+            .map(|(key, value)| (key.clone(), value.expose().to_string()))
+            .collect();
+
+        let validation = self.validate(
+            &template,
+            &ValidateOptions {
+                vars: vars.clone(),
+                var_files: options.var_files.clone(),
+                ..ValidateOptions::default()
+            },
+        )?;
+        let inspection = self.inspect_with_vars(&template, &vars, &options.var_files)?;
+        let missing_plugins = self.plugins().check_required(&template.path)?;
+
+        Ok(DryRunResult {
+            validation,
+            inspection,
+            missing_plugins,
+        })
+    }
+
+    /// Assemble the `packer build` command for a template
+    ///
+    /// Every build method goes through here, so they all check `options` with
+    /// [`BuildOptions::validate`] against the working directory first.
+    fn build_command(&self, template: &Path, options: &BuildOptions) -> Result<Command> {
+        options.validate_in(self.working_dir.as_deref())?;
+        #[cfg(any(feature = "tracing", feature = "logging"))]
+        log_build_options(options);
+
+        let mut cmd = self.base_command();
+        if options.isolated_env {
+            self.isolate_env(&mut cmd, &options.env_whitelist);
+        }
+        cmd.arg("build").arg("-machine-readable");
+        set_process_group(&mut cmd);
+        cmd.args(options.to_args());
+        match options.color {
+            None if self.env_no_color() => {
+                cmd.arg("-color=false");
+            }
+            Some(true) => {
+                cmd.env_remove("PACKER_NO_COLOR");
+            }
+            _ => {}
+        }
+        if options.disable_checkpoint {
+            cmd.env("CHECKPOINT_DISABLE", "1");
+        }
+        cmd.arg(template);
+        Ok(cmd)
+    }
+
+    /// Where the machine-readable output of a build with `options` goes, if not to memory
+    fn machine_readable_log_file(&self, options: &BuildOptions) -> Option<PathBuf> {
+        options
+            .machine_readable_log_file
+            .as_ref()
+            .map(|path| self.resolve_path(path))
+    }
+
+    /// Build images using a template, passing each line of output to `on_line` as it arrives
+    ///
+    /// Stdout lines are in packer's machine-readable format, which is also
+    /// what the returned [`BuildResult`] is assembled from.
+    pub fn build_with_output<F>(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+        mut on_line: F,
+    ) -> Result<BuildResult>
+    where
+        F: FnMut(OutputLine),
+    {
+        let cmd = self.build_command(&template.into().path, options)?;
+        let log_file = self.machine_readable_log_file(options);
+        let mut log = log_file
+            .as_deref()
+            .map(|path| std::fs::File::create(path).map(std::io::BufWriter::new))
+            .transpose()?;
+
+        let started = Instant::now();
+        let output = logged(cmd, self.stderr_limit, |mut cmd| {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            let mut child = self.launcher.spawn(&mut cmd)?;
+            let _forwarding = forward_signals(&mut child)?;
+
+            let (sender, receiver) = mpsc::channel();
+            let readers = [
+                spawn_line_reader(child.stdout.take(), sender.clone(), OutputLine::Stdout),
+                spawn_line_reader(child.stderr.take(), sender, OutputLine::Stderr),
+            ];
+
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            for line in receiver {
+                match (&line, &mut log) {
+                    (OutputLine::Stdout(text), Some(log)) => writeln!(log, "{}", text)?,
+                    (OutputLine::Stdout(text), None) => writeln!(stdout, "{}", text)?,
+                    (OutputLine::Stderr(text), _) => writeln!(stderr, "{}", text)?,
+                    (OutputLine::ExitStatus(_), _) => {}
+                }
+                on_line(line);
+            }
+
+            for reader in readers {
+                reader
+                    .join()
+                    .map_err(|_| PackerError::execution("output reader panicked"))??;
+            }
+            if let Some(log) = &mut log {
+                log.flush()?;
+            }
+
+            let mut output = command_output(std::process::Output {
+                status: child.wait()?,
+                stdout,
+                stderr,
+            })?;
+            if let Some(path) = &log_file {
+                output.stdout = read_machine_readable_log(path)?;
+            }
+            Ok(output)
+        })?;
+
+        Ok(build_result(output, started))
+    }
+
+    /// Build images using a template, reporting progress to `sink` as the build runs
+    ///
+    /// Events are read from packer's machine-readable output. Builders that
+    /// didn't report an error get their [`BuildEventSink::on_complete`] call
+    /// once packer exits, whether or not the build as a whole succeeded.
+    pub fn build_with_sink<F>(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+        sink: &mut F,
+    ) -> Result<BuildResult>
+    where
+        F: BuildEventSink + ?Sized,
+    {
+        let mut tracker = BuildEventTracker::default();
+        let result = self.build_with_output(template, options, |line| {
+            if let OutputLine::Stdout(text) = line {
+                tracker.feed(&text, sink);
+            }
+        });
+        tracker.finish(sink);
+        result
+    }
+
+    /// Build images using a template, killing packer if it runs longer than `timeout`
+    ///
+    /// On timeout packer is killed along with any processes it started (its
+    /// whole process group on Unix, its process tree on Windows) and
+    /// [`PackerError::TimeoutError`] is returned.
+    pub fn build_with_timeout(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+        timeout: Duration,
+    ) -> Result<BuildResult> {
+        let cmd = self.build_command(&template.into().path, options)?;
+        let log_file = self.machine_readable_log_file(options);
+
+        let started = Instant::now();
+        let output = self.execute_command_timeout(cmd, timeout, log_file.as_deref())?;
+
+        Ok(build_result(output, started))
+    }
+
+    /// Initialize a new Packer configuration, returning the plugins packer installed
+    ///
+    /// Plugins that were already installed are not listed.
+    pub fn init(
+        &self,
+        template: impl Into<Template>,
+        options: &InitOptions,
+    ) -> Result<Vec<PluginInstallRecord>> {
+        let cmd = self.init_command(&template.into().path, options);
+        Ok(parse_init_output(
+            &self.execute_command_capture(cmd)?.stdout,
+        ))
+    }
+
+    /// Assemble the `packer init` command for a template
+    fn init_command(&self, template: &Path, options: &InitOptions) -> Command {
+        let mut cmd = self.base_command();
+        cmd.arg("init");
+
+        if options.upgrade {
+            cmd.arg("-upgrade");
+        }
+        if options.force {
+            cmd.arg("-force");
+        }
+
+        cmd.arg(template);
+        cmd
+    }
+
+    /// Initialize a new Packer configuration with default options
+    pub fn init_default(&self, template: impl Into<Template>) -> Result<()> {
+        self.init(template, &InitOptions::default()).map(drop)
+    }
+
+    /// Initialize several templates, returning the plugins each one caused to be installed
+    ///
+    /// Every template is initialized even if some fail; failures are returned
+    /// together as [`PackerError::MultipleErrors`], labelled with the template
+    /// path. Templates that need a plugin an earlier one already installed
+    /// list nothing for it.
+    pub fn init_all<T: Into<Template> + Clone>(
+        &self,
+        templates: &[T],
+        options: &InitOptions,
+    ) -> Result<Vec<(PathBuf, Vec<PluginInstallRecord>)>> {
+        let mut results = Vec::with_capacity(templates.len());
+        let mut errors = Vec::new();
+
+        for template in templates {
+            let template = template.clone().into();
+            match self.init(&template, options) {
+                Ok(installed) => results.push((template.path, installed)),
+                Err(err) => errors.push((template.path.display().to_string(), err)),
+            }
+        }
+
+        PackerError::from_errors(errors)?;
+        Ok(results)
+    }
+
+    /// Validate a Packer template
+    pub fn validate(
+        &self,
+        template: impl Into<Template>,
+        options: &ValidateOptions,
+    ) -> Result<ValidationResult> {
+        let cmd = self.validate_command(&template.into().path, options);
+        let output = self.execute_command_capture(cmd)?;
+
+        Ok(validation_result(&output))
+    }
+
+    /// Assemble the `packer validate` command for a template
+    fn validate_command(&self, template: &Path, options: &ValidateOptions) -> Command {
+        let mut cmd = self.base_command();
+        cmd.arg("validate");
+
+        if options.syntax_only {
+            cmd.arg("-syntax-only");
+        }
+        if options.warn_on_undeclared_var {
+            cmd.arg("-warn-on-undeclared-var");
+        }
+
+        // Add variables
+        for (key, value) in &options.vars {
+            cmd.arg("-var").arg(format!("{}={}", key, value));
+        }
+
+        // Add var files
+        for var_file in &options.var_files {
+            cmd.arg(format!("-var-file={}", var_file.display()));
+        }
+
+        cmd.arg(template);
+        cmd
+    }
+
+    /// Validate a Packer template with default options
+    pub fn validate_default(&self, template: impl Into<Template>) -> Result<ValidationResult> {
+        self.validate(template, &ValidateOptions::default())
+    }
+
+    /// Inspect a template
+    pub fn inspect(&self, template: impl Into<Template>) -> Result<String> {
+        let cmd = self.inspect_command(&template.into().path, &[], &[]);
+        Ok(trim_packer_output(
+            &self.execute_command_capture(cmd)?.stdout,
+        ))
+    }
+
+    /// Inspect a template, parsing the builders, provisioners and post-processors it contains
+    ///
+    /// With [`Packer::with_inspect_cache`] an unchanged template is only
+    /// inspected once.
+    pub fn inspect_parsed(&self, template: impl Into<Template>) -> Result<TemplateInspection> {
+        let template = template.into();
+        let Some(cache) = &self.inspect_cache else {
+            return self.inspect_with_vars(template, &[], &[]);
+        };
+        let path = self.resolve_path(&template.path);
+        let Some(modified) = std::fs::metadata(&path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| metadata.modified().ok())
+        else {
+            return self.inspect_with_vars(template, &[], &[]);
+        };
+
+        let key = (path, modified);
+        if let Some(inspection) = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            return Ok(inspection.clone());
+        }
+        let inspection = self.inspect_with_vars(template, &[], &[])?;
+
+        let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.retain(|(cached, _), _| *cached != key.0);
+        cache.insert(key, inspection.clone());
+        Ok(inspection)
+    }
+
+    /// Inspect a template with `-var` overrides and var files applied
+    ///
+    /// HCL2 templates show variable values after the overrides, so this is
+    /// what a build with the same variables would see.
+    pub fn inspect_with_vars(
+        &self,
+        template: impl Into<Template>,
+        vars: &[(String, String)],
+        var_files: &[PathBuf],
+    ) -> Result<TemplateInspection> {
+        let template = template.into();
+        let cmd = self.inspect_command(&template.path, vars, var_files);
+        Ok(TemplateInspection {
+            kind: template.kind_at(&self.resolve_path(&template.path)),
+            ..parse_inspection(&self.execute_command_capture(cmd)?.stdout)
+        })
+    }
+
+    /// Assemble the `packer inspect` command for a template
+    fn inspect_command(
+        &self,
+        template: &Path,
+        vars: &[(String, String)],
+        var_files: &[PathBuf],
+    ) -> Command {
+        let mut cmd = self.base_command();
+        cmd.arg("inspect");
+        push_var_args(&mut cmd, vars, var_files);
+        cmd.arg(template);
+        cmd
+    }
+
+    /// Fix template
+    pub fn fix(&self, template: impl Into<Template>) -> Result<String> {
+        let mut cmd = self.base_command();
+        cmd.arg("fix").arg(template.into().path);
+        Ok(trim_packer_output(
+            &self.execute_command_capture(cmd)?.stdout,
+        ))
+    }
+
+    /// Fix a template and write the result back over the original file
+    ///
+    /// The fixed template is written to `<template>.tmp` and renamed into
+    /// place, so an interrupted run never leaves a half-written template.
+    pub fn fix_in_place(&self, template: impl Into<Template>) -> Result<()> {
+        let template = template.into().path;
+        let fixed = self.fix(&template)?;
+        if fixed.is_empty() {
+            return Err(PackerError::execution(format!(
+                "packer fix produced no output for {}",
+                template.display()
+            )));
+        }
+
+        let path = self.resolve_path(&template);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        std::fs::write(&tmp, format!("{}\n", fixed))?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Get version information
+    pub fn version(&self) -> Result<String> {
+        let mut cmd = self.base_command();
+        cmd.arg("version");
+        Ok(trim_packer_output(
+            &self.execute_command_capture(cmd)?.stdout,
+        ))
+    }
+
+    /// Create a base command with common configuration
+    fn base_command(&self) -> Command {
+        let mut cmd = Command::new(&self.executable);
+        if let Some(dir) = &self.working_dir {
+            cmd.current_dir(dir);
+        }
+        for key in &self.env_removals {
+            cmd.env_remove(key);
+        }
+        for (key, value) in &self.env_vars {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+
+    /// Clear the environment of `cmd`, keeping the passthrough and `whitelist` variables
+    ///
+    /// Variables set on the instance are applied again afterwards; ones it
+    /// removes stay removed even if whitelisted.
+    fn isolate_env(&self, cmd: &mut Command, whitelist: &[String]) {
+        cmd.env_clear();
+        let inherited = ISOLATED_ENV_PASSTHROUGH
+            .iter()
+            .copied()
+            .chain(whitelist.iter().map(String::as_str))
+            .filter(|key| !self.env_removals.iter().any(|removed| removed == key));
+        for key in inherited {
+            if let Some(value) = std::env::var_os(key) {
+                cmd.env(key, value);
+            }
+        }
+        for (key, value) in &self.env_vars {
+            cmd.env(key, value);
+        }
+    }
+
+    /// Execute a command, letting it inherit stdout unless a writer is configured
+    ///
+    /// Stderr is passed through to the configured writer or this process's
+    /// stderr, and its end is kept for the error if the command fails.
+    fn execute_command_silent(&self, cmd: Command) -> Result<()> {
+        logged(cmd, self.stderr_limit, |cmd| self.run_command_silent(cmd))
+    }
+
+    fn run_command_silent(&self, mut cmd: Command) -> Result<()> {
+        if self.stdout_writer.is_some() {
+            cmd.stdout(Stdio::piped());
+        }
+        cmd.stderr(Stdio::piped());
+        let stderr_writer = self
+            .stderr_writer
+            .clone()
+            .unwrap_or_else(|| SharedWriter(Arc::new(Mutex::new(Box::new(std::io::stderr())))));
+
+        let mut child = self.launcher.spawn(&mut cmd)?;
+        let _forwarding = forward_signals(&mut child)?;
+        let stdout = spawn_tee_reader(child.stdout.take(), self.stdout_writer.clone(), 0);
+        let stderr = spawn_tee_reader(child.stderr.take(), Some(stderr_writer), self.stderr_limit);
+        let status = child.wait()?;
+        join_reader(stdout)?;
+
+        command_output(std::process::Output {
+            status,
+            stdout: Vec::new(),
+            stderr: join_reader(stderr)?,
+        })
+        .map(drop)
+    }
+
+    /// Execute a command and capture its output, copying it to any configured writers
+    fn execute_command_capture(&self, cmd: Command) -> Result<CommandOutput> {
+        logged(cmd, self.stderr_limit, |cmd| self.run_command_capture(cmd))
+    }
+
+    fn run_command_capture(&self, mut cmd: Command) -> Result<CommandOutput> {
+        if self.stdout_writer.is_none() && self.stderr_writer.is_none() {
+            cmd.stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            return command_output(self.launcher.launch(&mut cmd)?);
+        }
+
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = self.launcher.spawn(&mut cmd)?;
+        let _forwarding = forward_signals(&mut child)?;
+        let stdout = spawn_tee_reader(child.stdout.take(), self.stdout_writer.clone(), usize::MAX);
+        let stderr = spawn_tee_reader(child.stderr.take(), self.stderr_writer.clone(), usize::MAX);
+        let status = child.wait()?;
+
+        command_output(std::process::Output {
+            status,
+            stdout: join_reader(stdout)?,
+            stderr: join_reader(stderr)?,
+        })
+    }
+
+    /// Execute a command with its stdout going to `path`, capturing stderr
+    ///
+    /// The returned stdout holds only the records of the file that
+    /// [`build_result`] needs, see [`retained_machine_readable`].
+    fn execute_command_to_file(&self, cmd: Command, path: &Path) -> Result<CommandOutput> {
+        let file = std::fs::File::create(path)?;
+        logged(cmd, self.stderr_limit, |mut cmd| {
+            cmd.stdin(Stdio::null()).stdout(file).stderr(Stdio::piped());
+            let mut output = command_output(self.launcher.launch(&mut cmd)?)?;
+            output.stdout = read_machine_readable_log(path)?;
+            Ok(output)
+        })
+    }
+
+    /// Execute a command and capture its output, killing it after `timeout`
+    ///
+    /// Like [`Packer::build_with_timeout`], the whole process group is killed.
+    /// With a `log_file` stdout goes there, as in
+    /// [`Packer::execute_command_to_file`].
+    fn execute_command_timeout(
+        &self,
+        cmd: Command,
+        timeout: Duration,
+        log_file: Option<&Path>,
+    ) -> Result<CommandOutput> {
+        let file = log_file.map(std::fs::File::create).transpose()?;
+        logged(cmd, self.stderr_limit, |mut cmd| {
+            match file {
+                Some(file) => cmd.stdout(file),
+                None => cmd.stdout(Stdio::piped()),
+            };
+            cmd.stderr(Stdio::piped());
+            set_process_group(&mut cmd);
+
+            let started = Instant::now();
+            let mut child = self.launcher.spawn(&mut cmd)?;
+            let _forwarding = forward_signals(&mut child)?;
+            let stdout = spawn_pipe_reader(child.stdout.take());
+            let stderr = spawn_pipe_reader(child.stderr.take());
+            let status = wait_with_timeout(&mut child, started, timeout)?;
+
+            let mut output = command_output(std::process::Output {
+                status,
+                stdout: join_reader(stdout)?,
+                stderr: join_reader(stderr)?,
+            })?;
+            if let Some(path) = log_file {
+                output.stdout = read_machine_readable_log(path)?;
+            }
+            Ok(output)
+        })
+    }
+}
+
+// Plugin management functionality
+impl Packer {
+    /// Plugin operations for this instance
+    pub fn plugins(&self) -> PluginManager<'_> {
+        PluginManager { packer: self }
+    }
+
+    /// Install a Packer plugin
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().install(..)`")]
+    pub fn plugin_install(&self, plugin: impl Into<PluginRef>) -> Result<()> {
+        self.plugins().install(plugin)
+    }
+
+    /// Remove a Packer plugin, see [`PluginManager::remove`]
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().remove(..)`")]
+    pub fn plugin_remove(&self, plugin_name: &str) -> Result<Option<PathBuf>> {
+        self.plugins().remove(plugin_name)
+    }
+
+    /// Upgrade an installed Packer plugin to the latest version
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().upgrade(..)`")]
+    pub fn plugin_upgrade(&self, plugin_name: &str) -> Result<()> {
+        self.plugins().upgrade(plugin_name)
+    }
+
+    /// Upgrade every installed Packer plugin
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().upgrade_all()`")]
+    pub fn plugin_upgrade_all(&self) -> Result<()> {
+        self.plugins().upgrade_all()
+    }
+
+    /// List the plugins a template requires
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().required(..)`")]
+    pub fn plugins_required(
+        &self,
+        template: impl Into<Template>,
+    ) -> Result<Vec<PluginRequirement>> {
+        self.plugins().required(template)
+    }
+
+    /// Install every plugin a template requires that isn't installed yet
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().install_required(..)`")]
+    pub fn install_required_plugins(&self, template: impl Into<Template>) -> Result<()> {
+        self.plugins().install_required(template)
+    }
+
+    /// List installed plugins
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().list()`")]
+    pub fn plugin_list(&self) -> Result<String> {
+        self.plugins().list()
+    }
+
+    /// List installed plugins as [`PluginInfo`] records
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().list_parsed()`")]
+    pub fn plugin_list_parsed(&self) -> Result<Vec<PluginInfo>> {
+        self.plugins().list_parsed()
+    }
+
+    /// Check whether a plugin is installed
+    #[deprecated(since = "0.3.0", note = "use `packer.plugins().is_installed(..)`")]
+    pub fn has_plugin(&self, name: &str) -> Result<bool> {
+        self.plugins().is_installed(name)
+    }
+}
+
+/// Plugin operations, returned by [`Packer::plugins`]
+#[derive(Debug, Clone, Copy)]
+pub struct PluginManager<'a> {
+    packer: &'a Packer,
+}
+
+impl PluginManager<'_> {
+    /// Install a Packer plugin, e.g. `"github.com/hashicorp/amazon"`
+    ///
+    /// Pass a [`PluginRef`] with a version to pin it; see also
+    /// [`PluginManager::install_version`].
+    pub fn install(&self, plugin: impl Into<PluginRef>) -> Result<()> {
+        let plugin = plugin.into();
+        match plugin.version.as_deref() {
+            Some(version) if version.trim().is_empty() => Err(PackerError::ConfigError(format!(
+                "Empty version for plugin {}",
+                plugin.source
+            ))),
+            Some(version) => self.run(&["plugin", "install", &plugin.source, version]),
+            None => self.run(&["plugin", "install", &plugin.source]),
+        }
+    }
+
+    /// Install a specific version of a Packer plugin, e.g. `v1.2.1`
+    pub fn install_version(&self, plugin_name: &str, version: &str) -> Result<()> {
+        self.install(PluginRef::new(plugin_name).with_version(version))
+    }
+
+    /// Remove a Packer plugin, returning the path of the binary packer deleted
+    ///
+    /// Returns `None` if packer succeeds without naming a file, which is
+    /// what it does when the plugin wasn't installed. Fails with
+    /// [`PackerError::PluginNotFound`] when packer rejects the plugin as
+    /// not installed instead.
+    pub fn remove(&self, plugin_name: &str) -> Result<Option<PathBuf>> {
+        let mut cmd = self.packer.base_command();
+        cmd.args(["plugin", "remove", plugin_name]);
+        match self.packer.execute_command_capture(cmd) {
+            Ok(output) => Ok(parse_removed_plugin(&output.stdout)),
+            Err(PackerError::ExecutionError { stderr, .. })
+                if stderr.to_lowercase().contains("no installed plugin") =>
+            {
+                Err(PackerError::PluginNotFound(plugin_name.to_string()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Upgrade an installed Packer plugin to the latest version
+    pub fn upgrade(&self, plugin_name: &str) -> Result<()> {
+        self.run(&["plugin", "upgrade", plugin_name])
+    }
+
+    /// Upgrade every installed Packer plugin
+    ///
+    /// All plugins are attempted; failures are reported together as
+    /// [`PackerError::MultipleErrors`], labelled with the plugin name.
+    pub fn upgrade_all(&self) -> Result<()> {
+        let errors: Vec<_> = self
+            .list_parsed()?
+            .into_iter()
+            .filter_map(|plugin| {
+                self.upgrade(&plugin.name)
+                    .err()
+                    .map(|err| (plugin.name, err))
+            })
+            .collect();
+
+        PackerError::from_errors(errors)
+    }
+
+    /// List installed plugins
+    pub fn list(&self) -> Result<String> {
+        let mut cmd = self.packer.base_command();
+        cmd.args(["plugin", "list"]);
+        Ok(trim_packer_output(
+            &self.packer.execute_command_capture(cmd)?.stdout,
+        ))
+    }
+
+    /// List installed plugins as [`PluginInfo`] records
+    pub fn list_parsed(&self) -> Result<Vec<PluginInfo>> {
+        Ok(parse_plugin_list(&self.list()?))
+    }
+
+    /// Check whether a plugin is installed
+    ///
+    /// `name` can be the full source (`github.com/hashicorp/amazon`) or just
+    /// the plugin name (`amazon`).
+    pub fn is_installed(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .list_parsed()?
+            .iter()
+            .any(|plugin| plugin.name == name || plugin.name.rsplit('/').next() == Some(name)))
+    }
+
+    /// List the plugins a template requires
+    ///
+    /// Needs packer 1.8.4 or later; older releases fail with
+    /// [`PackerError::VersionError`] without running the command.
+    pub fn required(&self, template: impl Into<Template>) -> Result<Vec<PluginRequirement>> {
+        self.packer
+            .require_feature(PackerFeature::PluginsRequired)?;
+        let mut cmd = self.packer.base_command();
+        cmd.args(["plugins", "required"]).arg(template.into().path);
+        let output = self.packer.execute_command_capture(cmd)?;
+
+        Ok(parse_plugin_requirements(&output.stdout))
+    }
+
+    /// The plugins a template requires that aren't installed yet
+    pub fn check_required(&self, template: impl Into<Template>) -> Result<Vec<PluginRequirement>> {
+        let installed = self.list_parsed()?;
+
+        Ok(self
+            .required(template)?
+            .into_iter()
+            .filter(|requirement| {
+                !installed
+                    .iter()
+                    .any(|plugin| plugin.name == requirement.source)
+            })
+            .collect())
+    }
+
+    /// Install every plugin a template requires that isn't installed yet
+    pub fn install_required(&self, template: impl Into<Template>) -> Result<()> {
+        for requirement in self.check_required(template)? {
+            self.install(requirement.source)?;
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, args: &[&str]) -> Result<()> {
+        let mut cmd = self.packer.base_command();
+        cmd.args(args);
+        self.packer.execute_command_silent(cmd)
+    }
+}
+
+// Console functionality
+impl Packer {
+    /// Start Packer console
+    ///
+    /// With `None` no template is passed, which packer 1.9 and later accept
+    /// for evaluating plain expressions. Older releases need a template.
+    pub fn console<T: Into<Template>>(
+        &self,
+        template: Option<T>,
+        options: &ConsoleOptions,
+    ) -> Result<()> {
+        let template = template.map(Into::into);
+        let cmd = self.console_command(template.as_ref().map(AsRef::as_ref), options);
+        self.execute_command_silent(cmd)
+    }
+
+    /// Start Packer console for a template
+    #[deprecated(since = "0.4.0", note = "use `packer.console(Some(template), ..)`")]
+    pub fn console_with_template(
+        &self,
+        template: impl Into<Template>,
+        options: &ConsoleOptions,
+    ) -> Result<()> {
+        self.console(Some(template.into()), options)
+    }
+
+    /// Assemble the `packer console` command, for a template if one is given
+    fn console_command(&self, template: Option<&Path>, options: &ConsoleOptions) -> Command {
+        let mut cmd = self.base_command();
+        cmd.arg("console");
+        push_var_args(&mut cmd, &options.vars, &options.var_files);
+        if let Some(template) = template {
+            cmd.arg(template);
+        }
+        cmd
+    }
+
+    /// Evaluate `expression` in the context of a template without an interactive console
+    ///
+    /// The expression is written to the console's stdin, one line per line of
+    /// `expression`, and the console's output is returned without the trailing
+    /// newline. Fails with [`PackerError::TimeoutError`] if the console has not
+    /// exited after [`ConsoleOptions::timeout`].
+    pub fn console_eval(
+        &self,
+        template: impl Into<Template>,
+        expression: &str,
+        options: &ConsoleOptions,
+    ) -> Result<String> {
+        let cmd = self.console_command(Some(&template.into().path), options);
+        let output = logged(cmd, self.stderr_limit, |mut cmd| {
+            cmd.stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            set_process_group(&mut cmd);
+
+            let started = Instant::now();
+            let mut child = self.launcher.spawn(&mut cmd)?;
+            let _forwarding = forward_signals(&mut child)?;
+            let stdout = spawn_pipe_reader(child.stdout.take());
+            let stderr = spawn_pipe_reader(child.stderr.take());
+
+            let mut input = expression.lines().collect::<Vec<_>>().join("\n");
+            input.push('\n');
+            // Written on a thread of its own, so a console that stops reading
+            // can't block us before the timeout starts counting
+            let stdin = spawn_pipe_writer(child.stdin.take(), input.into_bytes());
+
+            let status = wait_with_timeout(&mut child, started, options.timeout)?;
+            let _ = stdin.join();
+            command_output(std::process::Output {
+                status,
+                stdout: join_reader(stdout)?,
+                stderr: join_reader(stderr)?,
+            })
+        })?;
+
+        Ok(output.stdout.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+// HCL2 upgrade functionality
+impl Packer {
+    /// Upgrade HCL2 configuration
+    ///
+    /// Fails with [`PackerError::ConfigError`] if `template` is already HCL2,
+    /// and with [`PackerError::VersionError`] on packer older than 1.6.5.
+    ///
+    /// Returns the converted template: the contents of
+    /// [`Hcl2UpgradeOptions::output_file`] when one is set, packer's stdout
+    /// otherwise.
+    pub fn hcl2_upgrade(
+        &self,
+        template: impl Into<Template>,
+        options: &Hcl2UpgradeOptions,
+    ) -> Result<String> {
+        let template = template.into();
+        if template.kind_at(&self.resolve_path(&template.path)) == Some(TemplateKind::Hcl2) {
+            return Err(PackerError::ConfigError(format!(
+                "{} is already HCL2; hcl2_upgrade is only for legacy JSON templates",
+                template.path.display()
+            )));
+        }
+        self.require_feature(PackerFeature::Hcl2Upgrade)?;
+
+        let mut cmd = self.base_command();
+        cmd.arg("hcl2_upgrade");
+
+        if let Some(output_file) = &options.output_file {
+            cmd.arg(format!("-output-file={}", output_file.display()));
+        }
+        if options.with_annotations {
+            cmd.arg("-with-annotations");
+        }
+
+        cmd.arg(&template.path);
+        let output = self.execute_command_capture(cmd)?;
+
+        match &options.output_file {
+            Some(output_file) => Ok(trim_packer_output(&std::fs::read_to_string(
+                self.resolve_path(output_file),
+            )?)),
+            None => Ok(trim_packer_output(&output.stdout)),
+        }
+    }
+
+    /// Upgrade every legacy JSON template directly in `dir` to HCL2
+    ///
+    /// Each `name.json` is written to `name.pkr.hcl` next to it, ignoring
+    /// [`Hcl2UpgradeOptions::output_file`]. A relative `dir` is resolved
+    /// against the working directory. Returns the `(original, output)`
+    /// pairs. Stops at the first template that fails, returning
+    /// [`PackerError::PartialUpgrade`] with the templates upgraded so far.
+    pub fn hcl2_upgrade_dir<P: AsRef<std::path::Path>>(
+        &self,
+        dir: P,
+        options: &Hcl2UpgradeOptions,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let templates = find_templates(&self.resolve_path(dir.as_ref()), false)?
+            .into_iter()
+            .filter(|template| {
+                matches!(detect_template_kind(template), Ok(TemplateKind::LegacyJson))
+            });
+
+        let mut succeeded = Vec::new();
+        for template in templates {
+            let output = template.with_extension("pkr.hcl");
+            let file_options = Hcl2UpgradeOptions {
+                output_file: Some(output.clone()),
+                with_annotations: options.with_annotations,
+            };
+
+            let known = Template::new(&template).with_kind(TemplateKind::LegacyJson);
+            if let Err(error) = self.hcl2_upgrade(known, &file_options) {
+                return Err(PackerError::PartialUpgrade(Box::new(PartialUpgradeError {
+                    succeeded,
+                    error,
+                    failed_template: template,
+                })));
+            }
+            succeeded.push((template, output));
+        }
+
+        Ok(succeeded)
+    }
+}
+
+// Formatting functionality
+impl Packer {
+    /// Format HCL2 templates, either a single file or every template in a directory
+    ///
+    /// With [`FmtOptions::check`] set, files that would be reformatted are
+    /// reported as [`PackerError::FmtCheckFailed`] and left untouched.
+    /// Packer older than 1.6.0 fails with [`PackerError::VersionError`].
+    pub fn fmt(
+        &self,
+        template_or_dir: impl Into<Template>,
+        options: &FmtOptions,
+    ) -> Result<FmtResult> {
+        self.require_feature(PackerFeature::Fmt)?;
+        let mut cmd = self.base_command();
+        cmd.arg("fmt");
+
+        if options.check {
+            cmd.arg("-check");
+        }
+        if options.diff {
+            cmd.arg("-diff");
+        }
+        if options.recursive {
+            cmd.arg("-recursive");
+        }
+
+        cmd.arg(template_or_dir.into().path);
+
+        logged(cmd, self.stderr_limit, |mut cmd| {
+            cmd.stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            let output = self.launcher.launch(&mut cmd)?;
+            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            let changed_files = parse_fmt_files(&stdout);
+
+            // `packer fmt -check` exits with 3 when there is something to format
+            if options.check && output.status.code() == Some(3) {
+                return Err(PackerError::FmtCheckFailed {
+                    files: changed_files,
+                });
+            }
+            command_output(output)?;
+
+            Ok(FmtResult {
+                changed_files,
+                diff: options.diff.then(|| parse_fmt_diff(&stdout)),
+            })
+        })
+    }
+
+    /// Format each of `files`, running `packer fmt` once per file since it takes a single path
+    ///
+    /// `changed_files` lists every file that was reformatted, once, and
+    /// `diff` joins the diffs of all files. In check mode each file is still
+    /// checked, and [`PackerError::FmtCheckFailed`] names all that need
+    /// formatting. Any other failure stops at the file that caused it.
+    pub fn fmt_files<P: AsRef<std::path::Path>>(
+        &self,
+        files: &[P],
+        options: &FmtOptions,
+    ) -> Result<FmtResult> {
+        let mut changed_files: Vec<PathBuf> = Vec::new();
+        let mut diff = options.diff.then(String::new);
+        let mut unformatted = false;
+
+        for file in files {
+            let (changed, file_diff) = match self.fmt(file.as_ref(), options) {
+                Ok(result) => (result.changed_files, result.diff),
+                Err(PackerError::FmtCheckFailed { files }) => {
+                    unformatted = true;
+                    (files, None)
+                }
+                Err(err) => return Err(err),
+            };
+            for path in changed {
+                if !changed_files.contains(&path) {
+                    changed_files.push(path);
+                }
+            }
+            if let (Some(diff), Some(file_diff)) = (&mut diff, file_diff) {
+                diff.push_str(&file_diff);
+            }
+        }
+
+        if unformatted {
+            return Err(PackerError::FmtCheckFailed {
+                files: changed_files,
+            });
+        }
+        Ok(FmtResult {
+            changed_files,
+            diff,
+        })
+    }
+
+    /// Check whether templates are formatted, without modifying them
+    ///
+    /// Returns `Ok(false)` when any file would be reformatted; errors are
+    /// reserved for genuine failures such as unparseable templates.
+    pub fn fmt_check(&self, path: impl Into<Template>) -> Result<bool> {
+        let options = FmtOptions {
+            check: true,
+            ..Default::default()
+        };
+
+        match self.fmt(path, &options) {
+            Ok(_) => Ok(true),
+            Err(PackerError::FmtCheckFailed { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Version checking functionality
+impl Packer {
+    /// Check that the packer binary is present and responds, returning its version
+    ///
+    /// Runs `packer version`, giving up with [`PackerError::TimeoutError`]
+    /// after the health check timeout (5 seconds unless changed with
+    /// [`Packer::with_health_check_timeout`]). Meant as a readiness probe
+    /// before issuing real commands.
+    pub fn check_health(&self) -> Result<PackerVersion> {
+        let mut cmd = self.base_command();
+        cmd.arg("version");
+        self.execute_command_timeout(cmd, self.health_check_timeout, None)?
+            .stdout
+            .parse()
+    }
+
+    /// Get the installed Packer version as a [`PackerVersion`]
+    pub fn version_parsed(&self) -> Result<PackerVersion> {
+        self.version()?.parse()
+    }
+
+    /// Fail with [`PackerError::VersionError`] if the installed Packer is older than `min`
+    ///
+    /// The version is asked for once per instance and remembered. Its output
+    /// goes to no configured stdout or stderr writer, so those only see the
+    /// commands you run.
+    pub fn require_version(&self, min: &PackerVersion) -> Result<()> {
+        let found = self.probed_version()?;
+
+        if found < *min {
+            return Err(PackerError::VersionError {
+                found: found.to_string(),
+                required: min.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Fail with [`PackerError::VersionError`] if the installed Packer lacks `feature`
+    ///
+    /// Like [`Packer::require_version`], runs `packer version` at most once.
+    pub fn require_feature(&self, feature: PackerFeature) -> Result<()> {
+        self.require_version(&feature.min_version())
+    }
+
+    /// The installed version, running `packer version` past the configured writers the first time
+    fn probed_version(&self) -> Result<PackerVersion> {
+        if let Some(version) = self.version.get() {
+            return Ok(version.clone());
+        }
+
+        let mut cmd = self.base_command();
+        cmd.arg("version");
+        let output = logged(cmd, self.stderr_limit, |mut cmd| {
+            cmd.stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            command_output(self.launcher.launch(&mut cmd)?)
+        })?;
+        let version: PackerVersion = trim_packer_output(&output.stdout).parse()?;
+        Ok(self.version.get_or_init(|| version).clone())
+    }
+}
+
+// Batch functionality
+impl Packer {
+    /// Build several templates one after another, stopping at the first failure
+    ///
+    /// Returns one [`BuildResult`] per template, in order.
+    pub fn build_sequential<T: Into<Template> + Clone>(
+        &self,
+        templates: &[T],
+        options: &BuildOptions,
+    ) -> Result<Vec<BuildResult>> {
+        self.build_sequential_with_policy(templates, options, PartialFailurePolicy::StopOnFirst)
+    }
+
+    /// Build several templates one after another
+    ///
+    /// With [`PartialFailurePolicy::CollectAll`] the remaining templates are
+    /// still built after a failure, and all failures are returned together as
+    /// [`PackerError::MultipleErrors`], labelled with the template path.
+    pub fn build_sequential_with_policy<T: Into<Template> + Clone>(
+        &self,
+        templates: &[T],
+        options: &BuildOptions,
+        policy: PartialFailurePolicy,
+    ) -> Result<Vec<BuildResult>> {
+        let mut results = Vec::with_capacity(templates.len());
+        let mut errors = Vec::new();
+
+        for template in templates {
+            let template = template.clone().into();
+            match self.build(&template, options) {
+                Ok(result) => results.push(result),
+                Err(err) if policy == PartialFailurePolicy::StopOnFirst => return Err(err),
+                Err(err) => errors.push((template.path.display().to_string(), err)),
+            }
+        }
+
+        PackerError::from_errors(errors)?;
+        Ok(results)
+    }
+
+    /// Build several templates concurrently, running at most `max_concurrency` builds at a time
+    ///
+    /// Every template is built even if some fail. Results are returned in the
+    /// order of `templates`; failures are returned together as
+    /// [`PackerError::MultipleErrors`], labelled with the template path.
+    pub fn build_parallel<T: Into<Template> + Clone>(
+        &self,
+        templates: &[T],
+        options: &BuildOptions,
+        max_concurrency: usize,
+    ) -> Result<Vec<BuildResult>> {
+        if max_concurrency == 0 {
+            return Err(PackerError::ConfigError(
+                "max_concurrency must be at least 1".to_string(),
+            ));
+        }
+
+        let templates: Vec<Template> = templates.iter().cloned().map(Into::into).collect();
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let outcomes: Mutex<Vec<Option<Result<BuildResult>>>> =
+            Mutex::new(templates.iter().map(|_| None).collect());
+
+        std::thread::scope(|scope| {
+            for _ in 0..max_concurrency.min(templates.len()) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let Some(template) = templates.get(index) else {
+                        break;
+                    };
+                    let outcome = self.build(template, options);
+                    outcomes.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(outcome);
+                });
+            }
+        });
+
+        let mut results = Vec::with_capacity(templates.len());
+        let mut errors = Vec::new();
+        let outcomes = outcomes.into_inner().unwrap_or_else(|e| e.into_inner());
+        for (template, outcome) in templates.iter().zip(outcomes) {
+            match outcome.unwrap_or_else(|| Err(PackerError::execution("build thread panicked"))) {
+                Ok(result) => results.push(result),
+                Err(err) => errors.push((template.path.display().to_string(), err)),
+            }
+        }
+
+        PackerError::from_errors(errors)?;
+        Ok(results)
+    }
+
+    /// Validate every template directly in `dir`, without descending into subdirectories
+    ///
+    /// Shorthand for [`Packer::validate_all`] with `recursive` off.
+    pub fn validate_dir<P: AsRef<std::path::Path>>(
+        &self,
+        dir: P,
+        options: &ValidateOptions,
+    ) -> Result<HashMap<PathBuf, ValidationResult>> {
+        self.validate_all(dir.as_ref(), false, options)
+    }
+
+    /// Validate every template [`find_templates`] discovers in `dir`
+    ///
+    /// A relative `dir` is resolved against the working directory, and the
+    /// results are keyed by the resolved template paths. All templates are
+    /// validated even if some fail; failures are returned together as
+    /// [`PackerError::MultipleErrors`], labelled with the template path.
+    pub fn validate_all(
+        &self,
+        dir: &Path,
+        recursive: bool,
+        options: &ValidateOptions,
+    ) -> Result<HashMap<PathBuf, ValidationResult>> {
+        let mut results = HashMap::new();
+        let mut errors = Vec::new();
+
+        for template in find_templates(&self.resolve_path(dir), recursive)? {
+            match self.validate(&template, options) {
+                Ok(result) => {
+                    results.insert(template, result);
+                }
+                Err(err) => errors.push((template.display().to_string(), err)),
+            }
+        }
+
+        PackerError::from_errors(errors)?;
+        Ok(results)
+    }
+}
+
+// Async functionality
+#[cfg(feature = "tokio")]
+impl Packer {
+    /// Build images using a template without blocking the async runtime
+    ///
+    /// See [`Packer::build`].
+    pub async fn build_async(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+    ) -> Result<BuildResult> {
+        let cmd = self.build_command(&template.into().path, options)?;
+
+        let started = Instant::now();
+        let output = match self.machine_readable_log_file(options) {
+            Some(log_file) => self.execute_command_to_file_async(cmd, &log_file).await?,
+            None => self.execute_command_capture_async(cmd).await?,
+        };
+
+        Ok(build_result(output, started))
+    }
+
+    /// Build images using a template, stopping when `token` is cancelled
+    ///
+    /// Nothing runs until the returned [`BuildHandle`] is awaited. Cancelling
+    /// the token kills packer and everything it started, waits for packer to
+    /// exit so no zombie is left behind, and then fails with
+    /// [`PackerError::Cancelled`]; packer gets no chance to clean up. Dropping
+    /// the handle kills packer as well.
+    pub fn build_cancellable(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+        token: CancellationToken,
+    ) -> BuildHandle {
+        let cmd = self.build_command(&template.into().path, options);
+        let log_file = self.machine_readable_log_file(options);
+        let stderr_limit = self.stderr_limit;
+
+        BuildHandle {
+            inner: Box::pin(async move {
+                let cmd = cmd?;
+                let invocation = Invocation::start(&cmd);
+                let started = Instant::now();
+                let output = Self::execute_command_cancellable(cmd, &token, log_file.as_deref())
+                    .await
+                    .map_err(|e| e.truncate_stderr(stderr_limit));
+                invocation
+                    .finish(output)
+                    .map(|output| build_result(output, started))
+            }),
+        }
+    }
+
+    /// Execute a command on the tokio runtime and capture its output, killing it once `token` is cancelled
+    ///
+    /// With a `log_file` stdout goes there, as in [`Packer::execute_command_to_file`].
+    async fn execute_command_cancellable(
+        cmd: Command,
+        token: &CancellationToken,
+        log_file: Option<&Path>,
+    ) -> Result<CommandOutput> {
+        if token.is_cancelled() {
+            return Err(PackerError::Cancelled);
+        }
+
+        let mut cmd = tokio::process::Command::from(cmd);
+        match log_file {
+            Some(path) => cmd.stdout(std::fs::File::create(path)?),
+            None => cmd.stdout(Stdio::piped()),
+        };
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+
+        let finished = tokio::select! {
+            output = async {
+                tokio::join!(child.wait(), read_pipe(stdout), read_pipe(stderr))
+            } => Some(output),
+            _ = token.cancelled() => None,
+        };
+        let Some((status, stdout, stderr)) = finished else {
+            #[cfg(unix)]
+            if let Some(pid) = child.id() {
+                // SAFETY: killpg has no memory-safety preconditions; the group
+                // id is packer's pid because `build_command` starts it in its
+                // own process group.
+                unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+            }
+            // Also reaps packer, so it doesn't linger as a zombie
+            child.kill().await?;
+            return Err(PackerError::Cancelled);
+        };
+
+        let mut output = command_output(std::process::Output {
+            status: status?,
+            stdout: stdout?,
+            stderr: stderr?,
+        })?;
+        if let Some(path) = log_file {
+            output.stdout = read_machine_readable_log(path)?;
+        }
+        Ok(output)
+    }
+
+    /// Build images using a template, yielding each line of output as it arrives
+    ///
+    /// Lines from stdout and stderr are interleaved in the order they are
+    /// read. The stream ends with an [`OutputLine::ExitStatus`], or with an
+    /// error if packer could not be run. Dropping the stream kills packer.
+    ///
+    /// Packer runs on a task spawned with [`tokio::spawn`], so this must be
+    /// called from within a tokio runtime.
+    ///
+    /// The stream is the only copy of packer's output, so
+    /// [`BuildOptions::machine_readable_log_file`] isn't supported: with it
+    /// set, the stream yields a [`PackerError::ConfigError`] and packer never
+    /// runs.
+    pub fn build_stream(
+        &self,
+        template: impl Into<Template>,
+        options: &BuildOptions,
+    ) -> impl tokio_stream::Stream<Item = Result<OutputLine>> {
+        let cmd = match options.machine_readable_log_file {
+            Some(_) => Err(PackerError::ConfigError(
+                "build_stream does not support machine_readable_log_file; write the \
+                 streamed lines to a file instead"
+                    .to_string(),
+            )),
+            None => self.build_command(&template.into().path, options),
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            let streamed = match cmd {
+                Ok(cmd) => Self::stream_command(cmd, &sender).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = streamed {
+                let _ = sender.send(Err(e)).await;
+            }
+        });
+
+        tokio_stream::wrappers::ReceiverStream::new(receiver)
+    }
+
+    /// Initialize a new Packer configuration without blocking the async runtime
+    pub async fn init_async(
+        &self,
+        template: impl Into<Template>,
+        options: &InitOptions,
+    ) -> Result<Vec<PluginInstallRecord>> {
+        let cmd = self.init_command(&template.into().path, options);
+        let output = self.execute_command_capture_async(cmd).await?;
+
+        Ok(parse_init_output(&output.stdout))
+    }
+
+    /// Validate a Packer template without blocking the async runtime
+    pub async fn validate_async(
+        &self,
+        template: impl Into<Template>,
+        options: &ValidateOptions,
+    ) -> Result<ValidationResult> {
+        let cmd = self.validate_command(&template.into().path, options);
+        let output = self.execute_command_capture_async(cmd).await?;
+
+        Ok(validation_result(&output))
+    }
+
+    /// Inspect a template without blocking the async runtime
+    pub async fn inspect_async(&self, template: impl Into<Template>) -> Result<String> {
+        let cmd = self.inspect_command(&template.into().path, &[], &[]);
+        Ok(trim_packer_output(
+            &self.execute_command_capture_async(cmd).await?.stdout,
+        ))
+    }
+
+    /// Run a command on the tokio runtime, sending its output line by line
+    ///
+    /// Stops early, killing the process, once nobody is receiving.
+    async fn stream_command(
+        cmd: Command,
+        sender: &tokio::sync::mpsc::Sender<Result<OutputLine>>,
+    ) -> Result<()> {
+        use tokio::io::AsyncBufReadExt;
+
+        let invocation = Invocation::start(&cmd);
+        let mut child = tokio::process::Command::from(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .map(|pipe| tokio::io::BufReader::new(pipe).lines());
+        let mut stderr = child
+            .stderr
+            .take()
+            .map(|pipe| tokio::io::BufReader::new(pipe).lines());
+
+        while stdout.is_some() || stderr.is_some() {
+            let line = tokio::select! {
+                line = next_line(&mut stdout), if stdout.is_some() => line?.map(OutputLine::Stdout),
+                line = next_line(&mut stderr), if stderr.is_some() => line?.map(OutputLine::Stderr),
+            };
+            let Some(line) = line else {
+                continue;
+            };
+            if sender.send(Ok(line)).await.is_err() {
+                return Ok(());
+            }
+        }
+
+        let status = child.wait().await?;
+        let _ = invocation.finish(command_output(std::process::Output {
+            status,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }));
+        let _ = sender.send(Ok(OutputLine::ExitStatus(status))).await;
+        Ok(())
+    }
+
+    /// Like [`Packer::execute_command_to_file`], on the tokio runtime
+    async fn execute_command_to_file_async(
+        &self,
+        mut cmd: Command,
+        path: &Path,
+    ) -> Result<CommandOutput> {
+        cmd.stdin(Stdio::null())
+            .stdout(std::fs::File::create(path)?)
+            .stderr(Stdio::piped());
+        let invocation = Invocation::start(&cmd);
+        // Not `output()`, which would pipe stdout again
+        let output = match tokio::process::Command::from(cmd).spawn() {
+            Ok(child) => child.wait_with_output().await,
+            Err(e) => Err(e),
+        };
+        invocation.finish(
+            output
+                .map_err(PackerError::from)
+                .and_then(command_output)
+                .and_then(|mut output| {
+                    output.stdout = read_machine_readable_log(path)?;
+                    Ok(output)
+                })
+                .map_err(|e| e.truncate_stderr(self.stderr_limit)),
+        )
+    }
+
+    /// Execute a command on the tokio runtime and capture its output
+    async fn execute_command_capture_async(&self, cmd: Command) -> Result<CommandOutput> {
+        let invocation = Invocation::start(&cmd);
+        let output = tokio::process::Command::from(cmd).output().await;
+        invocation.finish(
+            output
+                .map_err(PackerError::from)
+                .and_then(command_output)
+                .map_err(|e| e.truncate_stderr(self.stderr_limit)),
+        )
+    }
+}
+
+/// A build started with [`Packer::build_cancellable`]
+///
+/// Await it for the [`BuildResult`].
+#[cfg(feature = "tokio")]
+pub struct BuildHandle {
+    inner: std::pin::Pin<Box<dyn std::future::Future<Output = Result<BuildResult>> + Send>>,
+}
+
+#[cfg(feature = "tokio")]
+impl std::future::Future for BuildHandle {
+    type Output = Result<BuildResult>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::fmt::Debug for BuildHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BuildHandle").finish_non_exhaustive()
+    }
+}
+
+// Test support functionality
+/// A stand-in for [`Packer`] that records calls instead of running packer
+///
+/// Lets crates built on this one test their packer usage without a packer
+/// binary. Each method records the template it was called with and returns
+/// the next result queued with the matching `push_*_result` method, or a
+/// default success when none is queued. Only available with the
+/// `test-support` feature.
+#[cfg(feature = "test-support")]
+#[derive(Debug, Default)]
+pub struct MockPacker {
+    calls: Mutex<Vec<MockCall>>,
+    build_results: Mutex<std::collections::VecDeque<Result<BuildResult>>>,
+    validate_results: Mutex<std::collections::VecDeque<Result<ValidationResult>>>,
+}
+
+/// A call made to a [`MockPacker`]
+#[cfg(feature = "test-support")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockCall {
+    /// The packer subcommand the call stands for, e.g. `build`
+    pub command: String,
+    pub template: PathBuf,
+}
+
+#[cfg(feature = "test-support")]
+impl MockPacker {
+    /// A mock with no calls recorded and no results queued
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the result of a later [`MockPacker::build`] call
+    pub fn push_build_result(&self, result: Result<BuildResult>) -> &Self {
+        lock(&self.build_results).push_back(result);
+        self
+    }
+
+    /// Queue the result of a later [`MockPacker::validate`] call
+    pub fn push_validate_result(&self, result: Result<ValidationResult>) -> &Self {
+        lock(&self.validate_results).push_back(result);
+        self
+    }
+
+    /// See [`Packer::build`]
+    pub fn build(
+        &self,
+        template: impl Into<Template>,
+        _options: &BuildOptions,
+    ) -> Result<BuildResult> {
+        self.record("build", &template.into().path);
+        lock(&self.build_results)
+            .pop_front()
+            .unwrap_or_else(|| Ok(BuildResult::default()))
+    }
+
+    /// See [`Packer::validate`]
+    pub fn validate(
+        &self,
+        template: impl Into<Template>,
+        _options: &ValidateOptions,
+    ) -> Result<ValidationResult> {
+        self.record("validate", &template.into().path);
+        lock(&self.validate_results)
+            .pop_front()
+            .unwrap_or_else(|| Ok(ValidationResult::default()))
+    }
+
+    /// See [`Packer::init`]
+    pub fn init(
+        &self,
+        template: impl Into<Template>,
+        _options: &InitOptions,
+    ) -> Result<Vec<PluginInstallRecord>> {
+        self.record("init", &template.into().path);
+        Ok(Vec::new())
+    }
+
+    /// Every call made so far, oldest first
+    pub fn calls(&self) -> Vec<MockCall> {
+        lock(&self.calls).clone()
+    }
+
+    /// The template passed to the most recent [`MockPacker::build`] call
+    pub fn last_build_template(&self) -> Option<PathBuf> {
+        lock(&self.calls)
+            .iter()
+            .rev()
+            .find(|call| call.command == "build")
+            .map(|call| call.template.clone())
+    }
+
+    /// How many times [`MockPacker::build`] has been called
+    pub fn build_call_count(&self) -> usize {
+        lock(&self.calls)
+            .iter()
+            .filter(|call| call.command == "build")
+            .count()
+    }
+
+    fn record(&self, command: &str, template: &Path) {
+        lock(&self.calls).push(MockCall {
+            command: command.to_string(),
+            template: template.to_path_buf(),
+        });
+    }
+}
+
+/// Lock `mutex`, carrying on if a panicking test poisoned it
+#[cfg(feature = "test-support")]
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `cmd` through `run`, reporting the invocation as described on [`Invocation`]
+///
+/// The stderr of a failure is cut down to `stderr_limit` bytes.
+fn logged<T>(
+    cmd: Command,
+    stderr_limit: usize,
+    run: impl FnOnce(Command) -> Result<T>,
+) -> Result<T> {
+    let invocation = Invocation::start(&cmd);
+    invocation.finish(run(cmd).map_err(|e| e.truncate_stderr(stderr_limit)))
+}
+
+/// Emit an event through `tracing` and/or `log`, whichever features are on
+///
+/// The braced fields are only attached to the `tracing` event; both get the
+/// formatted message. Fields must end with a comma.
+#[cfg(any(feature = "tracing", feature = "logging"))]
+macro_rules! emit_event {
+    ($level:ident, { $($field:tt)* }, $($message:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($field)* $($message)+);
+        #[cfg(feature = "logging")]
+        log::$level!($($message)+);
+    }};
+}
+
+/// A packer invocation, reported through `tracing` or `log` when those features are on
+///
+/// Starting one emits a `DEBUG` event with the command line, variable values
+/// redacted. Finishing emits an `INFO` event with the exit code and elapsed
+/// time, or an `ERROR` event with packer's stderr. Under `tracing` both
+/// belong to a `packer` span whose `command` and `template` fields hold the
+/// subcommand and its last argument.
+struct Invocation {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(any(feature = "tracing", feature = "logging"))]
+    command: String,
+    #[cfg(any(feature = "tracing", feature = "logging"))]
+    started: Instant,
+}
+
+impl Invocation {
+    #[cfg(any(feature = "tracing", feature = "logging"))]
+    fn start(cmd: &Command) -> Self {
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let command = args.first().cloned().unwrap_or_default();
+        let program = cmd.get_program().to_string_lossy();
+        let redacted = redact_args(&args);
+
+        let invocation = Invocation {
+            #[cfg(feature = "tracing")]
+            span: {
+                let template = args
+                    .iter()
+                    .skip(1)
+                    .last()
+                    .filter(|arg| !arg.starts_with('-'))
+                    .map_or("", String::as_str);
+                tracing::info_span!("packer", command = command.as_str(), template)
+            },
+            command,
+            started: Instant::now(),
+        };
+        invocation.in_scope(|| {
+            emit_event!(
+                debug,
+                { program = %program, args = %redacted, },
+                "running {} {}",
+                program,
+                redacted
+            )
+        });
+
+        invocation
+    }
+
+    #[cfg(not(any(feature = "tracing", feature = "logging")))]
+    fn start(_cmd: &Command) -> Self {
+        Invocation {}
+    }
+
+    #[cfg(any(feature = "tracing", feature = "logging"))]
+    fn finish<T>(self, result: Result<T>) -> Result<T> {
+        let elapsed = self.started.elapsed();
+        let command = &self.command;
+        self.in_scope(|| match &result {
+            Ok(_) => emit_event!(
+                info,
+                { exit_code = 0, ?elapsed, },
+                "packer {} finished with exit code 0 after {:?}",
+                command,
+                elapsed
+            ),
+            Err(PackerError::ExecutionError {
+                exit_code, stderr, ..
+            }) => emit_event!(
+                error,
+                { ?exit_code, ?elapsed, %stderr, },
+                "packer {} failed with exit code {:?} after {:?}: {}",
+                command,
+                exit_code,
+                elapsed,
+                stderr.trim_end()
+            ),
+            Err(err) => emit_event!(
+                error,
+                { ?elapsed, error = %err, },
+                "packer {} failed after {:?}: {}",
+                command,
+                elapsed,
+                err
+            ),
+        });
+
+        result
+    }
+
+    #[cfg(not(any(feature = "tracing", feature = "logging")))]
+    fn finish<T>(self, result: Result<T>) -> Result<T> {
+        result
+    }
+
+    /// Run `f` inside the invocation's span, if there is one
+    #[cfg(any(feature = "tracing", feature = "logging"))]
+    fn in_scope(&self, f: impl FnOnce()) {
+        #[cfg(feature = "tracing")]
+        self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        f();
+    }
+}
+
+/// Emit a `DEBUG` event with the options of a build, secrets redacted
+#[cfg(any(feature = "tracing", feature = "logging"))]
+fn log_build_options(options: &BuildOptions) {
+    let redacted = options.redacted_clone();
+    emit_event!(debug, { options = ?redacted, }, "build options: {:?}", redacted);
+}
+
+/// Join `args` for logging, replacing every `-var` value with `***`
+///
+/// Any variable may hold a secret, and by the time a command is logged the
+/// [`SecretVar`] wrappers are gone.
+#[cfg(any(feature = "tracing", feature = "logging"))]
+fn redact_args(args: &[String]) -> String {
+    let mut after_var = false;
+    args.iter()
+        .map(|arg| {
+            let redacted = match arg.split_once('=') {
+                Some((name, _)) if after_var => format!("{}=***", name),
+                _ => arg.clone(),
+            };
+            after_var = arg == "-var";
+            redacted
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Read `pipe` line by line on a background thread, sending each line wrapped by `wrap`
+fn spawn_line_reader<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    sender: mpsc::Sender<OutputLine>,
+    wrap: fn(String) -> OutputLine,
+) -> std::thread::JoinHandle<std::io::Result<()>> {
+    std::thread::spawn(move || {
+        let Some(pipe) = pipe else {
+            return Ok(());
+        };
+
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\n', '\r']).to_string();
+            // The receiver only goes away once the caller has stopped listening
+            if sender.send(wrap(text)).is_err() {
+                break;
+            }
+            line.clear();
+        }
+
+        Ok(())
+    })
+}
+
+/// Read `pipe` to the end on a background thread
+fn spawn_pipe_reader<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    })
+}
+
+/// Write `data` to `pipe` on a background thread, then close it
+///
+/// Write errors are dropped: a process that exits before reading everything
+/// tells what went wrong better through its exit status than a broken pipe does.
+fn spawn_pipe_writer<W: Write + Send + 'static>(
+    pipe: Option<W>,
+    data: Vec<u8>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.write_all(&data);
+        }
+    })
+}
+
+/// Read `pipe` to the end on a background thread, copying everything to `writer`
+///
+/// The last `keep` bytes of the data are also returned.
+fn spawn_tee_reader<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    writer: Option<SharedWriter>,
+    keep: usize,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let Some(mut pipe) = pipe else {
+            return Ok(buffer);
+        };
+        let mut chunk = [0u8; 8192];
+        loop {
+            let read = pipe.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            if let Some(writer) = &writer {
+                writer.write_all(&chunk[..read])?;
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            if buffer.len() > keep {
+                buffer.drain(..buffer.len() - keep);
+            }
+        }
+        Ok(buffer)
+    })
+}
+
+/// Read an async pipe to the end; a missing pipe reads as empty
+#[cfg(feature = "tokio")]
+async fn read_pipe<R: tokio::io::AsyncRead + Unpin>(pipe: Option<R>) -> std::io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut buffer = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buffer).await?;
+    }
+    Ok(buffer)
+}
+
+/// Read the next line of an async pipe, closing it (setting it to `None`) at the end
+#[cfg(feature = "tokio")]
+async fn next_line<R: tokio::io::AsyncBufRead + Unpin>(
+    pipe: &mut Option<tokio::io::Lines<R>>,
+) -> std::io::Result<Option<String>> {
+    let Some(lines) = pipe else {
+        return Ok(None);
+    };
+    let line = lines.next_line().await?;
+    if line.is_none() {
+        *pipe = None;
+    }
+    Ok(line)
+}
+
+/// Wait for a reader thread, surfacing its I/O error
+fn join_reader(handle: std::thread::JoinHandle<std::io::Result<Vec<u8>>>) -> Result<Vec<u8>> {
+    handle
+        .join()
+        .map_err(|_| PackerError::execution("output reader panicked"))?
+        .map_err(PackerError::from)
+}
+
+/// Start the command in its own process group so [`kill_process_tree`] reaches its children
+fn set_process_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// Wait for `child`, killing it and its children once `timeout` has passed since `started`
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    started: Instant,
+    timeout: Duration,
+) -> Result<std::process::ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            kill_process_tree(child);
+            child.wait()?;
+            return Err(PackerError::TimeoutError { elapsed: timeout });
+        }
+        std::thread::sleep((timeout - elapsed).min(Duration::from_millis(50)));
+    }
+}
+
+/// Kill a child started in its own process group, along with everything in that group
+#[cfg(unix)]
+fn kill_process_tree(child: &mut std::process::Child) {
+    // SAFETY: killpg has no memory-safety preconditions; the group id is the
+    // child's pid because it was spawned with `process_group(0)`.
+    let killed = unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == 0;
+    if !killed {
+        let _ = child.kill();
+    }
+}
+
+/// Kill a child along with every process it started
+#[cfg(windows)]
+fn kill_process_tree(child: &mut std::process::Child) {
+    let killed = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if !killed {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_process_tree(child: &mut std::process::Child) {
+    let _ = child.kill();
+}
+
+/// Guard that forwards SIGTERM and SIGINT to a child until dropped, see [`signal_forwarding`]
+///
+/// If every slot is taken the child is killed and an error returned, rather
+/// than leaving it running without the signals it relies on to clean up.
+#[cfg(unix)]
+fn forward_signals(
+    child: &mut std::process::Child,
+) -> std::io::Result<signal_forwarding::Forwarding> {
+    match signal_forwarding::register(child.id() as libc::pid_t) {
+        Some(forwarding) => Ok(forwarding),
+        None => {
+            kill_process_tree(child);
+            let _ = child.wait();
+            Err(std::io::Error::other(format!(
+                "Cannot forward signals to more than {} packer processes at once",
+                signal_forwarding::SLOTS
+            )))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn forward_signals(_child: &mut std::process::Child) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Forwarding of SIGTERM and SIGINT to children in their own process group
+///
+/// Such a child doesn't get the signals a terminal or a service manager sends
+/// to this process, so on shutdown it would keep running, orphaned, along
+/// with the cloud resources it holds. While at least one registered child
+/// runs, SIGTERM and SIGINT are caught and sent on to every registered group
+/// with `killpg`, which gives packer the chance to run its cleanup. When the
+/// last child exits the previous handlers are restored and a caught signal
+/// is raised again, so it still takes effect, just after packer is done.
+///
+/// Children sharing this process's group get terminal signals directly and
+/// are not registered, so they never see a signal twice.
+#[cfg(unix)]
+mod signal_forwarding {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Mutex;
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+    pub(crate) const SLOTS: usize = 64;
+
+    /// Process groups to forward to; 0 marks a free slot
+    static GROUPS: [AtomicI32; SLOTS] = [const { AtomicI32::new(0) }; SLOTS];
+    /// The last signal caught, raised again once the last child is done
+    static PENDING: AtomicI32 = AtomicI32::new(0);
+    /// Number of registered children, and the handlers they replaced
+    static STATE: Mutex<(usize, Vec<libc::sigaction>)> = Mutex::new((0, Vec::new()));
+
+    extern "C" fn forward(signal: libc::c_int) {
+        PENDING.store(signal, Ordering::SeqCst);
+        for group in &GROUPS {
+            let pgid = group.load(Ordering::SeqCst);
+            if pgid > 0 {
+                // SAFETY: killpg is async-signal-safe and has no memory-safety
+                // preconditions.
+                unsafe { libc::killpg(pgid, signal) };
+            }
+        }
+    }
+
+    /// Keeps a child registered until dropped
+    #[derive(Debug)]
+    pub(crate) struct Forwarding {
+        slot: Option<usize>,
+    }
+
+    /// Register `pid`, or return `None` if all [`SLOTS`] are taken
+    pub(crate) fn register(pid: libc::pid_t) -> Option<Forwarding> {
+        // SAFETY: getpgid has no memory-safety preconditions.
+        if unsafe { libc::getpgid(pid) } != pid {
+            return Some(Forwarding { slot: None });
+        }
+
+        let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+        let slot = GROUPS.iter().position(|group| {
+            group
+                .compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        slot?;
+        if state.0 == 0 {
+            state.1 = SIGNALS.iter().map(|signal| install(*signal)).collect();
+        }
+        state.0 += 1;
+
+        Some(Forwarding { slot })
+    }
+
+    /// Install [`forward`] for `signal`, returning the handler it replaces
+    fn install(signal: libc::c_int) -> libc::sigaction {
+        // SAFETY: both sigaction structs are fully initialised (zeroed, then
+        // filled in) and `forward` only uses async-signal-safe operations.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, &action, &mut previous);
+            previous
+        }
+    }
+
+    impl Drop for Forwarding {
+        fn drop(&mut self) {
+            let Some(slot) = self.slot else { return };
+            GROUPS[slot].store(0, Ordering::SeqCst);
+
+            let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+            state.0 -= 1;
+            if state.0 > 0 {
+                return;
+            }
+
+            for (signal, previous) in SIGNALS.iter().zip(state.1.drain(..)) {
+                // SAFETY: `previous` is the action sigaction handed back when
+                // `forward` was installed.
+                unsafe { libc::sigaction(*signal, &previous, std::ptr::null_mut()) };
+            }
+            let pending = PENDING.swap(0, Ordering::SeqCst);
+            if pending != 0 {
+                // SAFETY: raise has no memory-safety preconditions.
+                unsafe { libc::raise(pending) };
+            }
+        }
+    }
+}
+
+/// Turn the captured output of a command into a result
+fn command_output(output: std::process::Output) -> Result<CommandOutput> {
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let message = match stderr.trim() {
+            "" => format!("Command failed with {}", output.status),
+            trimmed => trimmed.to_string(),
+        };
+        return Err(PackerError::ExecutionError {
+            message,
+            exit_code: output.status.code(),
+            stderr,
+        });
+    }
+
+    Ok(CommandOutput::from(output))
+}
+
+/// Keep at most the last `limit` bytes of `text`, noting how much was cut
+fn truncate_front(text: String, limit: usize) -> String {
+    if text.len() <= limit {
+        return text;
+    }
+    let mut start = text.len() - limit;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[{} bytes omitted]\n{}", start, &text[start..])
+}
+
+/// Quote `arg` for a POSIX shell, leaving it alone when no quoting is needed
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_=.,/:@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// The plugin binary `packer plugins remove` reports deleting, if any
+///
+/// Packer prints the path of every file it removes; the binary is the one
+/// named `packer-plugin-*`.
+fn parse_removed_plugin(output: &str) -> Option<PathBuf> {
+    output
+        .lines()
+        .map(str::trim)
+        .map(PathBuf::from)
+        .find(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("packer-plugin-"))
+        })
+}
+
+/// Packer's output without the whitespace around it, e.g. the trailing newline (`\r\n` on Windows)
+fn trim_packer_output(output: &str) -> String {
+    output.trim().to_string()
+}
+
+/// Pass `-var` overrides and `-var-file`s to an `inspect` or `console` command
+fn push_var_args(cmd: &mut Command, vars: &[(String, String)], var_files: &[PathBuf]) {
+    for (key, value) in vars {
+        cmd.arg("-var").arg(format!("{}={}", key, value));
+    }
+    for var_file in var_files {
+        cmd.arg(format!("-var-file={}", var_file.display()));
+    }
+}
+
+/// Build a [`BuildResult`] from the output of a `packer build` started at `started`
+fn build_result(output: CommandOutput, started: Instant) -> BuildResult {
+    BuildResult {
+        artifacts: parse_artifacts(&output.stdout),
+        duration: started.elapsed(),
+        build_times: parse_build_times(&output.stdout),
+        provisioner_log: output.stderr,
+    }
+}
+
+/// Read back the [`retained_machine_readable`] lines of the log file at `path`
+fn read_machine_readable_log(path: &Path) -> Result<String> {
+    Ok(retained_machine_readable(BufReader::new(
+        std::fs::File::open(path)?,
+    ))?)
+}
+
+/// The lines of a machine-readable log that [`build_result`] looks at
+///
+/// Artifact and timing records are kept, plus the first and last record of
+/// every other target for its build time, so a long log is never held in
+/// memory as a whole.
+fn retained_machine_readable(reader: impl BufRead) -> std::io::Result<String> {
+    let mut kept = String::new();
+    let mut spans: Vec<(String, String, String)> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let Some(event) = parse_machine_readable(&line).pop() else {
+            continue;
+        };
+        if event.target.is_empty() {
+            continue;
+        }
+        if event.kind() == MachineReadableEventKind::Artifact || event.event_type == "timing" {
+            kept.push_str(&line);
+            kept.push('\n');
+            continue;
+        }
+        match spans
+            .iter_mut()
+            .find(|(target, _, _)| *target == event.target)
+        {
+            Some((_, _, last)) => *last = line,
+            None => spans.push((event.target, line.clone(), line)),
+        }
+    }
+
+    for (_, first, last) in spans {
+        kept.push_str(&format!("{}\n{}\n", first, last));
+    }
+    Ok(kept)
+}
+
+/// Build a [`ValidationResult`] from the output of `packer validate`
+fn validation_result(output: &CommandOutput) -> ValidationResult {
+    let mut warnings = parse_warnings(&output.stdout);
+    warnings.extend(parse_warnings(&output.stderr));
+
+    let mut undeclared_vars = parse_undeclared_vars(&output.stdout);
+    undeclared_vars.extend(parse_undeclared_vars(&output.stderr));
+
+    ValidationResult {
+        warnings,
+        undeclared_vars,
+    }
+}
+
+/// (De)serialise `-var` pairs as a `{ "name": "value" }` map, keeping their order
+#[cfg(feature = "serde")]
+mod vars_map {
+    use serde::de::{MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::marker::PhantomData;
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        vars: &[(String, V)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(vars.len()))?;
+        for (key, value) in vars {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, V)>, D::Error> {
+        struct VarsVisitor<V>(PhantomData<V>);
+
+        impl<'de, V: Deserialize<'de>> Visitor<'de> for VarsVisitor<V> {
+            type Value = Vec<(String, V)>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of variable names to values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut vars = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    vars.push(entry);
+                }
+                Ok(vars)
+            }
+        }
+
+        deserializer.deserialize_map(VarsVisitor(PhantomData))
+    }
+}
+
+/// Work out how long each builder took from machine-readable build output
+fn parse_build_times(output: &str) -> HashMap<String, Duration> {
+    let mut spans: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut reported: HashMap<String, Duration> = HashMap::new();
+
+    for event in parse_machine_readable(output) {
+        if event.target.is_empty() {
+            continue;
+        }
+
+        if event.event_type == "timing" {
+            let seconds = event
+                .data
+                .first()
+                .and_then(|value| value.trim_end_matches('s').parse::<f64>().ok())
+                .filter(|seconds| seconds.is_finite() && *seconds >= 0.0);
+            if let Some(seconds) = seconds {
+                reported.insert(event.target, Duration::from_secs_f64(seconds));
+            }
+            continue;
+        }
+
+        let span = spans
+            .entry(event.target)
+            .or_insert((event.timestamp, event.timestamp));
+        span.0 = span.0.min(event.timestamp);
+        span.1 = span.1.max(event.timestamp);
+    }
+
+    let mut times: HashMap<String, Duration> = spans
+        .into_iter()
+        .map(|(target, (first, last))| (target, Duration::from_secs(last - first)))
+        .collect();
+    times.extend(reported);
+    times
+}
+
+/// Collect the artifacts reported in `-machine-readable` build output
+///
+/// Artifact events carry `index,key,value...` as their data.
+fn parse_artifacts(output: &str) -> Vec<BuildArtifact> {
+    let mut artifacts: Vec<((String, String), BuildArtifact)> = Vec::new();
+
+    for event in parse_machine_readable(output) {
+        if event.kind() != MachineReadableEventKind::Artifact || event.data.len() < 2 {
+            continue;
+        }
+
+        let key = (event.target.clone(), event.data[0].clone());
+        let value = |index: usize| event.data.get(index).cloned().unwrap_or_default();
+
+        let position = match artifacts.iter().position(|(k, _)| *k == key) {
+            Some(position) => position,
+            None => {
+                artifacts.push((
+                    key,
+                    BuildArtifact {
+                        builder_name: event.target.clone(),
+                        ..Default::default()
+                    },
+                ));
+                artifacts.len() - 1
+            }
+        };
+        let artifact = &mut artifacts[position].1;
+
+        match event.data[1].as_str() {
+            "builder-id" => artifact.artifact_type = value(2),
+            "id" => artifact.artifact_id = value(2),
+            "file" => artifact.files.push(PathBuf::from(value(3))),
+            _ => {}
+        }
+    }
+
+    artifacts
+        .into_iter()
+        .map(|(_, artifact)| artifact)
+        .collect()
+}
+
+/// Parse the `Installed plugin <source> v<version> in "<path>"` lines `packer init` prints
+fn parse_init_output(output: &str) -> Vec<PluginInstallRecord> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim().strip_prefix("Installed plugin ")?;
+            let (source, rest) = rest.split_once(' ')?;
+            let (version, path) = rest.split_once(" in ").unwrap_or((rest, ""));
+
+            Some(PluginInstallRecord {
+                source: source.to_string(),
+                version: version.trim_start_matches('v').to_string(),
+                path: PathBuf::from(path.trim().trim_matches('"')),
+            })
+        })
+        .collect()
+}
+
+/// Parse the list of installed plugins
+///
+/// Understands both `<name> v<version> [path]` lines and bare plugin binary
+/// paths such as
+/// `.../plugins/github.com/hashicorp/amazon/packer-plugin-amazon_v1.2.1_x5.0_linux_amd64`.
+fn parse_plugin_list(output: &str) -> Vec<PluginInfo> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let first = parts.next()?;
+
+            if let Some(version) = parts.next().and_then(|part| part.strip_prefix('v')) {
+                return Some(PluginInfo {
+                    name: first.to_string(),
+                    version: version.to_string(),
+                    path: parts.next().map(PathBuf::from).unwrap_or_default(),
+                });
+            }
+
+            let path = PathBuf::from(first);
+            let file_name = path.file_name()?.to_str()?;
+            let rest = file_name.strip_prefix("packer-plugin-")?;
+            let (_, version) = rest.split_once("_v")?;
+            let version = version.split('_').next()?.to_string();
+
+            let components: Vec<_> = path
+                .parent()?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let name = match components
+                .iter()
+                .rposition(|component| component == "plugins")
+            {
+                Some(index) if index + 1 < components.len() => components[index + 1..].join("/"),
+                _ => rest.split('_').next()?.to_string(),
+            };
+
+            Some(PluginInfo {
+                name,
+                version,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Parse `packer plugins required` output, one `<source> [constraint]` per line
+fn parse_plugin_requirements(output: &str) -> Vec<PluginRequirement> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let source = parts.next()?.to_string();
+            let version_constraint = parts.collect::<Vec<_>>().join(" ");
+
+            Some(PluginRequirement {
+                source,
+                version_constraint,
+            })
+        })
+        .collect()
+}
+
+/// Parse the human-readable output of `packer inspect`
+///
+/// Handles both the HCL2 layout (`sources:`, `provisioners:` and
+/// `post-processors:` under each build) and the legacy JSON layout
+/// (`Builders:`, `Provisioners:`, `Post-processors:`).
+fn parse_inspection(output: &str) -> TemplateInspection {
+    enum Section {
+        Builders,
+        Provisioners,
+        PostProcessors,
+        Other,
+    }
+
+    let mut inspection = TemplateInspection::default();
+    let mut section = Section::Other;
+
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        match line.to_lowercase().as_str() {
+            "builders:" | "sources:" => {
+                section = Section::Builders;
+                continue;
+            }
+            "provisioners:" => {
+                section = Section::Provisioners;
+                continue;
+            }
+            "post-processors:" => {
+                section = Section::PostProcessors;
+                continue;
+            }
+            _ => {}
+        }
+
+        let is_heading = line.starts_with('>') || (line.ends_with(':') && !line.contains(' '));
+        if is_heading
+            || line.starts_with("Note:")
+            || line.ends_with("variables and their defaults:")
+        {
+            // Post-processor chains are listed under numbered headings like `0:`
+            if !(matches!(section, Section::PostProcessors)
+                && line.trim_end_matches(':').parse::<u32>().is_ok())
+            {
+                section = Section::Other;
+            }
+            continue;
+        }
+        if line.starts_with('<') {
+            // Placeholders such as `<No provisioners>`
+            continue;
+        }
+
+        let (type_name, name) = parse_component(line);
+        match section {
+            Section::Builders => inspection.builders.push(BuilderInfo { type_name, name }),
+            Section::Provisioners => inspection
+                .provisioners
+                .push(ProvisionerInfo { type_name, name }),
+            Section::PostProcessors => inspection
+                .post_processors
+                .push(PostProcessorInfo { type_name, name }),
+            Section::Other => {}
+        }
+    }
+
+    inspection
+}
+
+/// Split an inspected component into its type and name
+///
+/// Accepts `type.name` (HCL2 sources), `name (type)` (legacy named
+/// components) and a bare `type`.
+fn parse_component(line: &str) -> (String, String) {
+    if let Some((name, type_name)) = line
+        .strip_suffix(')')
+        .and_then(|rest| rest.split_once(" ("))
+    {
+        return (type_name.trim().to_string(), name.trim().to_string());
+    }
+    if let Some((type_name, name)) = line.split_once('.') {
+        return (type_name.to_string(), name.to_string());
+    }
+    (line.to_string(), line.to_string())
+}
+
+/// Collect the file names `packer fmt` lists, skipping any diff lines around them
+fn parse_fmt_files(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| is_fmt_file_line(line))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The diff `packer fmt -diff` printed, without the file names listed between diffs
+fn parse_fmt_diff(output: &str) -> String {
+    output
+        .lines()
+        .filter(|line| !is_fmt_file_line(line.trim_end()))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// Whether a line of `packer fmt` output names a reformatted file
+fn is_fmt_file_line(line: &str) -> bool {
+    !line.is_empty() && !line.starts_with(['-', '+', '@', ' ', '\\']) && line.ends_with(".hcl")
+}
+
+/// Collect the summaries of `Warning: ...` diagnostics in packer output
+fn parse_warnings(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|line| line.trim_start_matches(['│', '╷', '╵', ' ']).trim_end())
+        .filter_map(|line| line.strip_prefix("Warning:"))
+        .map(|warning| warning.trim().to_string())
+        .collect()
+}
+
+/// Extract the variable names from packer's `Undefined ... variable` warnings
+///
+/// The name is the first quoted word in the warning's detail text, e.g.
+/// `A "region" variable was passed in but was not found.`
+fn parse_undeclared_vars(output: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut in_warning = false;
+
+    for line in output.lines() {
+        let line = line.trim_start_matches(['│', '╷', '╵', ' ']).trim_end();
+        if let Some(title) = line.strip_prefix("Warning:") {
+            let title = title.trim();
+            in_warning = title.starts_with("Undefined") && title.ends_with("variable");
+        } else if line.starts_with("Error:") {
+            in_warning = false;
+        } else if in_warning {
+            let name = line
+                .split_once('"')
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(name, _)| name);
+            if let Some(name) = name {
+                names.push(name.to_string());
+                in_warning = false;
+            }
+        }
+    }
+
+    names
+}
+
+/// Check that `dir` is an existing directory, naming it `what` in the error otherwise
+fn existing_dir(dir: PathBuf, what: &str) -> Result<PathBuf> {
+    if !dir.is_dir() {
+        return Err(PackerError::ConfigError(format!(
+            "{} directory {} does not exist",
+            what,
+            dir.display()
+        )));
+    }
+
+    Ok(dir)
+}
+
+/// Find the packer templates in `dir`, sorted by path
+///
+/// Matches `*.pkr.hcl` and `*.pkr.json` files, plus `*.json` files that look
+/// like legacy JSON templates (they have a `"builders"` key), which leaves out
+/// variable files and other unrelated JSON. Subdirectories are only searched
+/// when `recursive` is set.
+pub fn find_templates(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut walker = walkdir::WalkDir::new(dir).min_depth(1);
+    if !recursive {
+        walker = walker.max_depth(1);
+    }
+
+    let mut templates = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(std::io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy();
+        let is_template = if name.ends_with(".pkr.hcl") || name.ends_with(".pkr.json") {
+            true
+        } else if name.ends_with(".json") {
+            std::fs::read_to_string(entry.path())
+                .is_ok_and(|contents| is_legacy_json_template(&contents))
+        } else {
+            false
+        };
+        if is_template {
+            templates.push(entry.into_path());
+        }
+    }
+
+    templates.sort();
+    Ok(templates)
+}
+
+/// Tell whether `path` is an HCL2 or a legacy JSON template
+///
+/// `.pkr.hcl` and `.pkr.json` files and directories are HCL2. Other `.json`
+/// files are read and must look like a legacy template, i.e. an object with a
+/// `builders` key; anything else is a [`PackerError::ConfigError`].
+pub fn detect_template_kind(path: &Path) -> Result<TemplateKind> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if name.ends_with(".pkr.hcl") || name.ends_with(".pkr.json") || path.is_dir() {
+        return Ok(TemplateKind::Hcl2);
+    }
+
+    if name.ends_with(".json") && is_legacy_json_template(&std::fs::read_to_string(path)?) {
+        return Ok(TemplateKind::LegacyJson);
+    }
+
+    Err(PackerError::ConfigError(format!(
+        "{} is not a packer template",
+        path.display()
+    )))
+}
+
+/// Whether JSON `contents` look like a legacy template: an object with a `builders` key
+fn is_legacy_json_template(contents: &str) -> bool {
+    contents.trim_start().starts_with('{') && contents.contains("\"builders\"")
+}
+
+/// Path of the Packer binary in the current directory
+fn local_executable() -> PathBuf {
+    if cfg!(target_os = "windows") {
+        PathBuf::from("./packer.exe")
+    } else {
+        PathBuf::from("./packer")
+    }
+}
+
+/// Whether `path` is a file the OS will run: the executable bit on Unix, an
+/// extension listed in `PATHEXT` on Windows
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+    let extension = match path.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => return false,
+    };
+
+    path.is_file()
+        && pathext
+            .split(';')
+            .any(|known| known.eq_ignore_ascii_case(&extension))
+}
+
+/// Whether the packer binary at `path` runs and reports `version`
+fn is_packer_installed(path: &Path, version: &str) -> bool {
+    Command::new(path)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .parse::<PackerVersion>()
+                .ok()
+        })
+        .is_some_and(|installed| installed.to_string() == version)
+}
+
+/// How much of packer's stderr a failed command keeps unless configured otherwise
+const DEFAULT_STDERR_LIMIT: usize = 4096;
+
+/// How long [`Packer::check_health`] waits unless configured otherwise
+const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Packer release installed when no binary can be found, and the SHA-256 of
+// its zip for each platform, keyed by HashiCorp's `<os>_<arch>` name. The
+// checksums are copied from the GPG-signed `packer_<version>_SHA256SUMS` of
+// the release, so a server that hands out a tampered zip can't also vouch
+// for it. Moving to a new release means updating the version and the table
+// together. `PACKER_BUILD_VERSION` and `PACKER_DOWNLOAD_BASE_URL` override
+// the release; they, and platforms missing from the table, need the expected
+// checksum in `PACKER_CHECKSUM`.
+const PACKER_VERSION: &str = "1.7.8";
+const PACKER_CHECKSUMS: &[(&str, &str)] = &[];
+const PACKER_RELEASES_URL: &str = "https://releases.hashicorp.com/packer";
+
+/// The pinned checksum of the [`PACKER_VERSION`] zip for `platform`, e.g. `linux_amd64`
+fn pinned_checksum(platform: &str) -> Option<&'static str> {
+    PACKER_CHECKSUMS
+        .iter()
+        .find(|(pinned, _)| *pinned == platform)
+        .map(|(_, checksum)| *checksum)
+}
+
+/// The packer version to install and the base URL its releases are served from
+fn packer_release() -> Result<(String, String)> {
+    let version = match std::env::var("PACKER_BUILD_VERSION") {
+        Ok(version) if !version.trim().is_empty() => {
+            let version = version.trim().trim_start_matches('v');
+            version.parse::<PackerVersion>()?;
+            version.to_string()
+        }
+        _ => PACKER_VERSION.to_string(),
+    };
+
+    let base_url = match std::env::var("PACKER_DOWNLOAD_BASE_URL") {
+        Ok(url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_string(),
+        _ => PACKER_RELEASES_URL.to_string(),
+    };
+    if !base_url
+        .get(..8)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+    {
+        return Err(PackerError::ConfigError(format!(
+            "PACKER_DOWNLOAD_BASE_URL must be an https:// URL, got {}",
+            base_url
+        )));
+    }
+
+    Ok((version, base_url))
+}
+
+/// Download and unpack packer into [`packer_cache_dir`], returning the binary
+///
+/// The release is unpacked as `packer-<version>`, so nothing is written to
+/// the current directory or the source tree, projects wanting different
+/// versions don't overwrite each other's binary, and switching back to a
+/// version unpacked before needs no download. Nothing is downloaded if the
+/// binary there already has the wanted version.
+/// With `PACKER_OFFLINE=1` nothing is downloaded either and
+/// [`PackerError::NotFound`] is returned, leaving it to the user to provide
+/// the binary.
+///
+/// The release zip is kept in [`packer_cache_dir`], and a cached zip is
+/// reused as long as it still matches the checksum from [`release_checksum`].
+fn install_packer() -> Result<PathBuf> {
+    if std::env::var("PACKER_OFFLINE").is_ok_and(|value| value == "1") {
+        return Err(PackerError::not_found(Vec::new()));
+    }
+
+    let (os, arch) = (std::env::consts::OS, std::env::consts::ARCH);
+    let (version, base_url) = packer_release()?;
+    let installed = installed_executable(&version);
+    if is_packer_installed(&installed, &version) {
+        return Ok(installed);
+    }
+    let platform = release_platform(os, arch).ok_or_else(|| {
+        PackerError::ConfigError(format!(
+            "Cannot install Packer on unsupported platform {}/{}",
+            os, arch
+        ))
+    })?;
+    let zip_url = packer_download_url(&base_url, &platform, &version);
+    let zip_name = zip_url.rsplit('/').next().unwrap_or_default().to_string();
+
+    let cache_dir = packer_cache_dir();
+    std::fs::create_dir_all(&cache_dir)?;
+    let zip_path = cache_dir.join(&zip_name);
+
+    let expected = release_checksum(&version, &base_url, &platform, user_checksum().as_deref())?;
+
+    let cached = std::fs::read(&zip_path)
+        .is_ok_and(|data| verify_checksum(&zip_name, &data, &expected).is_ok());
+    if !cached {
+        download(&zip_url, &zip_path)?;
+        if let Err(e) = verify_checksum(&zip_name, &std::fs::read(&zip_path)?, &expected) {
+            let _ = std::fs::remove_file(&zip_path);
+            return Err(e);
+        }
+    }
+
+    // Unpack into a directory of our own so a concurrent install of another
+    // version never sees a partly written binary
+    let staging = cache_dir.join(format!(".packer-{}.{}", version, std::process::id()));
+    let extracted = extract_zip(&zip_path, &staging).and_then(|()| {
+        let binary = staging.join(local_executable().file_name().unwrap_or_default());
+        std::fs::rename(binary, &installed).map_err(PackerError::from)
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    extracted?;
+
+    Ok(installed)
+}
+
+/// The SHA-256 the zip of a release must have
+///
+/// `user` is the value of `PACKER_CHECKSUM`, which wins when set. Otherwise
+/// the default release is checked against [`PACKER_CHECKSUMS`]. Any other
+/// release, or the default one from a mirror, needs `PACKER_CHECKSUM`: the
+/// server handing out the zip can't be the one vouching for it.
+fn release_checksum(
+    version: &str,
+    base_url: &str,
+    platform: &str,
+    user: Option<&str>,
+) -> Result<String> {
+    if let Some(checksum) = user {
+        if checksum.len() != 64 || !checksum.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(PackerError::ConfigError(format!(
+                "PACKER_CHECKSUM must be a hex-encoded SHA-256, got {:?}",
+                checksum
+            )));
+        }
+        return Ok(checksum.to_lowercase());
+    }
+
+    let zip_name = format!("packer_{}_{}.zip", version, platform);
+    if version != PACKER_VERSION || base_url != PACKER_RELEASES_URL {
+        return Err(PackerError::ConfigError(format!(
+            "Set PACKER_CHECKSUM to the SHA-256 of {} to install a release other than \
+             Packer {} from {}",
+            zip_name, PACKER_VERSION, PACKER_RELEASES_URL
+        )));
+    }
+    pinned_checksum(platform)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            PackerError::ConfigError(format!(
+                "No checksum of {} is pinned in this crate; set PACKER_CHECKSUM to install it",
+                zip_name
+            ))
+        })
+}
+
+/// The value of `PACKER_CHECKSUM`, if it is set
+fn user_checksum() -> Option<String> {
+    std::env::var("PACKER_CHECKSUM")
+        .ok()
+        .map(|checksum| checksum.trim().to_string())
+        .filter(|checksum| !checksum.is_empty())
+}
+
+/// Where [`install_packer`] unpacks a release: `packer-<version>` in [`packer_cache_dir`]
+fn installed_executable(version: &str) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        packer_cache_dir().join(format!("packer-{}.exe", version))
+    } else {
+        packer_cache_dir().join(format!("packer-{}", version))
+    }
+}
+
+/// Where downloaded packer releases are kept
+///
+/// `PACKER_CACHE_PATH` if set, else a `packer_rs` directory in the user's
+/// cache directory, so the cache doesn't depend on the current directory.
+/// Without a home directory it falls back to `packer_rs` in the temporary
+/// directory, never to a path inside the (possibly read-only) source tree.
+fn packer_cache_dir() -> PathBuf {
+    match std::env::var_os("PACKER_CACHE_PATH") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => user_cache_dir()
+            .map(|dir| dir.join("packer_rs"))
+            .unwrap_or_else(|| std::env::temp_dir().join("packer_rs")),
+    }
+}
+
+/// The per-user cache directory: `%LOCALAPPDATA%` on Windows,
+/// `~/Library/Caches` on macOS, `$XDG_CACHE_HOME` or `~/.cache` elsewhere
+fn user_cache_dir() -> Option<PathBuf> {
+    let absolute = |name: &str| {
+        std::env::var_os(name)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+    };
+
+    if cfg!(target_os = "windows") {
+        absolute("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        absolute("HOME").map(|home| home.join("Library").join("Caches"))
+    } else {
+        absolute("XDG_CACHE_HOME").or_else(|| absolute("HOME").map(|home| home.join(".cache")))
+    }
+}
+
+/// HashiCorp's `<os>_<arch>` name for a Rust `os`/`arch` pair (as in `std::env::consts`)
+///
+/// Returns `None` for platforms HashiCorp does not build packer for.
+fn release_platform(os: &str, arch: &str) -> Option<String> {
+    let os = match os {
+        "macos" => "darwin",
+        "linux" | "windows" | "freebsd" | "openbsd" | "netbsd" | "solaris" => os,
+        _ => return None,
+    };
+    let arch = match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        _ => return None,
+    };
+
+    Some(format!("{}_{}", os, arch))
+}
+
+/// URL of the release zip for a platform named by [`release_platform`]
+fn packer_download_url(base_url: &str, platform: &str, version: &str) -> String {
+    format!(
+        "{}/{}/packer_{}_{}.zip",
+        base_url, version, version, platform
+    )
+}
+
+/// Download `url` to `dest` over HTTPS, validating the server certificate
+fn download(url: &str, dest: &Path) -> Result<()> {
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| PackerError::execution(format!("Failed to download {}: {}", url, e)))?;
+
+    // Write next to `dest` first so an interrupted download never looks
+    // complete, under a name no concurrent download of the same file shares
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(format!(".{}.part", std::process::id()));
+    let partial = PathBuf::from(partial);
+
+    let mut file = std::fs::File::create(&partial)?;
+    std::io::copy(&mut response.into_reader(), &mut file)?;
+    file.flush()?;
+    drop(file);
+
+    std::fs::rename(partial, dest)?;
+    Ok(())
+}
+
+/// Unpack every file in the zip archive at `zip_path` into `dest`
+///
+/// Unix permissions stored in the archive are restored, so the packer binary
+/// stays executable.
+fn extract_zip(zip_path: &Path, dest: &Path) -> Result<()> {
+    let extract_error = |e: zip::result::ZipError| {
+        PackerError::execution(format!("Failed to extract {}: {}", zip_path.display(), e))
+    };
+
+    let mut archive =
+        zip::ZipArchive::new(std::fs::File::open(zip_path)?).map_err(extract_error)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(extract_error)?;
+        let Some(name) = entry.enclosed_name() else {
+            continue;
+        };
+        let path = dest.join(name);
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::io::copy(&mut entry, &mut std::fs::File::create(&path)?)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `data` hashes to the hex-encoded SHA-256 `expected`
+fn verify_checksum(file_name: &str, data: &[u8], expected: &str) -> Result<()> {
+    let actual = sha256_hex(data);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(PackerError::ChecksumMismatch {
+            file: file_name.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Hex-encoded SHA-256 of `data`
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+    use tempfile::TempDir;
+
+    static_assertions::assert_impl_all!(Packer: Send, Sync);
+
+    // Serialises tests that touch process-wide state (current dir, env vars)
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Holds the env lock and puts the current dir and any variables changed
+    // through it back on drop, so a failing assertion can't leak them into
+    // later tests
+    struct TestEnv {
+        original_dir: Option<PathBuf>,
+        original_vars: Vec<(&'static str, Option<std::ffi::OsString>)>,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl TestEnv {
+        fn new() -> Self {
+            TestEnv {
+                original_dir: None,
+                original_vars: Vec::new(),
+                _guard: lock_env(),
+            }
+        }
+
+        fn set_current_dir(&mut self, dir: &Path) {
+            if self.original_dir.is_none() {
+                self.original_dir = Some(std::env::current_dir().unwrap());
+            }
+            std::env::set_current_dir(dir).unwrap();
+        }
+
+        fn set_var(&mut self, name: &'static str, value: impl AsRef<std::ffi::OsStr>) {
+            if !self.original_vars.iter().any(|(saved, _)| *saved == name) {
+                self.original_vars.push((name, std::env::var_os(name)));
+            }
+            std::env::set_var(name, value);
+        }
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            for (name, value) in self.original_vars.drain(..).rev() {
+                match value {
+                    Some(value) => std::env::set_var(name, value),
+                    None => std::env::remove_var(name),
+                }
+            }
+            if let Some(dir) = self.original_dir.take() {
+                let _ = std::env::set_current_dir(dir);
+            }
+        }
+    }
+
+    // Helper function to create a test environment
+    fn setup_test_env() -> TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    // Helper function to create a Packer for a binary that is never run
+    fn dummy_packer() -> Packer {
+        Packer {
+            executable: PathBuf::from("dummy"),
+            working_dir: None,
+            env_vars: Vec::new(),
+            env_removals: Vec::new(),
+            stdout_writer: None,
+            stderr_writer: None,
+            health_check_timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            stderr_limit: DEFAULT_STDERR_LIMIT,
+            launcher: Arc::new(RealProcessLauncher),
+            inspect_cache: None,
+            version: OnceLock::new(),
+        }
+    }
+
+    // Helper function to write an executable shell script standing in for packer
+    #[cfg(unix)]
+    fn write_mock_packer(dir: &Path, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("packer");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    // Like `write_mock_packer`, but `packer version` reports a release with every
+    // `PackerFeature`, so commands pass their version check first
+    #[cfg(unix)]
+    fn write_current_mock_packer(dir: &Path, script: &str) -> PathBuf {
+        write_mock_packer(
+            dir,
+            &format!(
+                "[ \"$1\" = version ] && {{ echo 'Packer v1.9.4'; exit 0; }}\n{}",
+                script
+            ),
+        )
+    }
+
+    // Helper function to create a mock packer that records its arguments, one per line
+    #[cfg(unix)]
+    fn write_recording_packer(dir: &Path) -> PathBuf {
+        write_mock_packer(
+            dir,
+            &format!(
+                "printf '%s\\n' \"$@\" > '{}'",
+                dir.join("args.txt").display()
+            ),
+        )
+    }
+
+    #[cfg(unix)]
+    fn recorded_args(dir: &Path) -> Vec<String> {
+        std::fs::read_to_string(dir.join("args.txt"))
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_build_options_builder() {
+        let options = BuildOptionsBuilder::default()
+            .debug(true)
+            .force(true)
+            .parallel_builds(Some(2))
+            .vars(vec![("key".to_string(), "value".to_string())])
+            .build()
+            .unwrap();
+
+        assert!(options.debug);
+        assert!(options.force);
+        assert_eq!(options.parallel_builds, Some(2));
+        assert_eq!(options.vars.len(), 1);
+        assert_eq!(options.vars[0].0, "key");
+        assert_eq!(options.vars[0].1, "value");
+    }
+
+    #[test]
+    fn test_packer_new_not_found() {
+        let mut env = TestEnv::new();
+
+        // Create a clean test directory
+        let test_dir = setup_test_env();
+        println!("{test_dir:#?}");
+
+        env.set_current_dir(test_dir.path());
+        env.set_var("PACKER_CACHE_PATH", test_dir.path().join("cache"));
+
+        // Now we know for sure there's no packer executable here
+        let packer = Packer::new();
+
+        assert!(packer.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_new_finds_executable_on_path() {
+        let mut env = TestEnv::new();
+
+        let work_dir = setup_test_env();
+        let bin_dir = setup_test_env();
+        let mock = write_mock_packer(bin_dir.path(), "echo 'Packer v1.10.3'");
+
+        env.set_current_dir(work_dir.path());
+        env.set_var("PATH", bin_dir.path());
+        env.set_var("PACKER_CACHE_PATH", work_dir.path().join("cache"));
+
+        let packer = Packer::new();
+
+        assert_eq!(packer.unwrap().executable, mock);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_executable() {
+        let mut env = TestEnv::new();
+
+        let work_dir = setup_test_env();
+        let bin_dir = setup_test_env();
+        let cache_dir = setup_test_env();
+        env.set_current_dir(work_dir.path());
+        env.set_var("PATH", bin_dir.path());
+        env.set_var("PACKER_CACHE_PATH", cache_dir.path());
+
+        let missing = Packer::find_executable();
+        // Not executable, so PATH lookup must skip it
+        std::fs::write(bin_dir.path().join("packer"), "").unwrap();
+        let not_executable = Packer::find_executable();
+        let mock = write_mock_packer(bin_dir.path(), "exit 0");
+        let on_path = Packer::find_executable();
+        write_mock_packer(work_dir.path(), "exit 0");
+        let local = Packer::find_executable();
+        let installed = installed_executable(PACKER_VERSION);
+        std::fs::rename(write_mock_packer(cache_dir.path(), "exit 0"), &installed).unwrap();
+        let cached = Packer::find_executable();
+
+        assert!(matches!(missing, Err(PackerError::NotFound(_))));
+        assert!(matches!(not_executable, Err(PackerError::NotFound(_))));
+        assert_eq!(on_path.unwrap(), mock);
+        assert_eq!(local.unwrap(), local_executable());
+        assert_eq!(cached.unwrap(), installed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_new_offline() {
+        let mut env = TestEnv::new();
+
+        let work_dir = setup_test_env();
+        let bin_dir = setup_test_env();
+        env.set_current_dir(work_dir.path());
+        env.set_var("PATH", bin_dir.path());
+        env.set_var("PACKER_CACHE_PATH", work_dir.path().join("cache"));
+        env.set_var("PACKER_OFFLINE", "1");
+
+        let packer = Packer::new();
+
+        match packer {
+            Err(PackerError::NotFound(not_found)) => assert_eq!(
+                not_found.searched_paths,
+                [
+                    work_dir
+                        .path()
+                        .join("cache")
+                        .join(format!("packer-{}", PACKER_VERSION)),
+                    local_executable(),
+                    bin_dir.path().join("packer")
+                ]
+            ),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+        assert!(!work_dir.path().join("packer.zip").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_packer_installed() {
+        let test_dir = setup_test_env();
+        let mock = write_mock_packer(test_dir.path(), "echo '1.7.8'");
+        assert!(is_packer_installed(&mock, "1.7.8"));
+        assert!(!is_packer_installed(&mock, "1.10.3"));
+        assert!(!is_packer_installed(
+            &test_dir.path().join("missing"),
+            "1.7.8"
+        ));
+    }
+
+    #[test]
+    fn test_packer_new_with_path() {
+        let test_dir = setup_test_env();
+        let missing = test_dir.path().join("packer");
+        assert!(matches!(
+            Packer::new_with_path(&missing),
+            Err(PackerError::NotFound(_))
+        ));
+
+        std::fs::write(&missing, "").unwrap();
+        let packer = Packer::new_with_path(&missing).unwrap();
+        assert_eq!(packer.executable, missing);
+    }
+
+    #[test]
+    fn test_packer_from_env_missing_binary() {
+        let _guard = lock_env();
+
+        let test_dir = setup_test_env();
+        std::env::set_var("PACKER_BINARY", test_dir.path().join("no-such-packer"));
+        let packer = Packer::from_env();
+        std::env::remove_var("PACKER_BINARY");
+
+        assert!(matches!(packer, Err(PackerError::ConfigError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_from_env() {
+        let _guard = lock_env();
+
+        let test_dir = setup_test_env();
+        let mock = write_mock_packer(test_dir.path(), "exit 0");
+        std::env::set_var("PACKER_BINARY", &mock);
+        let packer = Packer::from_env();
+        std::env::remove_var("PACKER_BINARY");
+
+        assert_eq!(packer.unwrap().executable, mock);
+    }
+
+    #[test]
+    fn test_packer_with_working_dir() {
+        let test_dir = setup_test_env();
+        let packer = dummy_packer().with_working_dir(test_dir.path());
+
+        assert_eq!(packer.working_dir.unwrap(), test_dir.path());
+    }
+
+    #[test]
+    fn test_packer_with_env() {
+        let packer = dummy_packer()
+            .with_env("AWS_PROFILE", "ci")
+            .with_envs([("PACKER_TEST_A", "1"), ("PACKER_TEST_B", "2")]);
+
+        let cmd = packer.base_command();
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(envs.len(), 3);
+        assert!(envs.contains(&("AWS_PROFILE".as_ref(), Some("ci".as_ref()))));
+        assert!(envs.contains(&("PACKER_TEST_B".as_ref(), Some("2".as_ref()))));
+        assert!(std::env::var_os("PACKER_TEST_A").is_none());
+    }
+
+    #[test]
+    fn test_packer_log_settings() {
+        let packer = dummy_packer();
+
+        let logging = packer
+            .clone()
+            .with_packer_log(1)
+            .with_log_path("/tmp/packer.log");
+        let cmd = logging.base_command();
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert!(envs.contains(&("PACKER_LOG".as_ref(), Some("1".as_ref()))));
+        assert!(envs.contains(&("PACKER_LOG_PATH".as_ref(), Some("/tmp/packer.log".as_ref()))));
+
+        let silenced = logging.with_packer_log(0);
+        let cmd = silenced.base_command();
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert!(envs.contains(&("PACKER_LOG".as_ref(), None)));
+    }
+
+    #[test]
+    fn test_packer_cache_and_tmp_dirs() {
+        let test_dir = setup_test_env();
+        let packer = dummy_packer();
+
+        let missing = test_dir.path().join("missing");
+        assert!(matches!(
+            packer.clone().with_cache_dir(&missing),
+            Err(PackerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            packer.clone().with_tmp_dir(&missing),
+            Err(PackerError::ConfigError(_))
+        ));
+
+        let packer = packer
+            .with_cache_dir(test_dir.path())
+            .unwrap()
+            .with_tmp_dir(test_dir.path())
+            .unwrap();
+        let cmd = packer.base_command();
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert!(envs.contains(&(
+            "PACKER_CACHE_DIR".as_ref(),
+            Some(test_dir.path().as_os_str())
+        )));
+        assert!(envs.contains(&("PACKER_TMP_DIR".as_ref(), Some(test_dir.path().as_os_str()))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_env_reaches_child() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo \"$PACKER_TEST_GREETING\"",
+        ))
+        .unwrap()
+        .with_env("PACKER_TEST_GREETING", "hello");
+
+        assert_eq!(packer.version().unwrap(), "hello");
+        assert!(std::env::var_os("PACKER_TEST_GREETING").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_text_output_is_trimmed() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "printf 'output for %s\\r\\n\\n' \"$1\"",
+        ))
+        .unwrap();
+
+        assert_eq!(packer.version().unwrap(), "output for version");
+        assert_eq!(
+            packer.inspect("template.pkr.hcl").unwrap(),
+            "output for inspect"
+        );
+        assert_eq!(packer.fix("template.json").unwrap(), "output for fix");
+        assert_eq!(packer.plugins().list().unwrap(), "output for plugin");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_builder() {
+        let test_dir = setup_test_env();
+        let mock = write_mock_packer(test_dir.path(), "exit 0");
+
+        let packer = Packer::builder()
+            .executable(&mock)
+            .working_dir(test_dir.path())
+            .env_var("AWS_PROFILE", "ci")
+            .env_vars([("AWS_REGION", "us-west-2")])
+            .log_level(1)
+            .log_path("packer.log")
+            .cache_dir(test_dir.path())
+            .tmp_dir(test_dir.path())
+            .build()
+            .unwrap();
+
+        assert_eq!(packer.executable, mock);
+        assert_eq!(packer.working_dir.as_deref(), Some(test_dir.path()));
+        let cmd = packer.base_command();
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(envs.len(), 6);
+        assert!(envs.contains(&("PACKER_LOG".as_ref(), Some("1".as_ref()))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_builder_validation() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_dir = setup_test_env();
+        let missing = test_dir.path().join("missing");
+        assert!(matches!(
+            Packer::builder().executable(&missing).build(),
+            Err(PackerError::NotFound(_))
+        ));
+
+        let not_executable = test_dir.path().join("packer.txt");
+        std::fs::write(&not_executable, "").unwrap();
+        std::fs::set_permissions(&not_executable, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            Packer::builder().executable(&not_executable).build(),
+            Err(PackerError::ConfigError(_))
+        ));
+
+        let mock = write_mock_packer(test_dir.path(), "exit 0");
+        assert!(matches!(
+            Packer::builder()
+                .executable(&mock)
+                .cache_dir(&missing)
+                .build(),
+            Err(PackerError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_options_default() {
+        let options = BuildOptions::default();
+        assert!(!options.debug);
+        assert!(!options.force);
+        assert_eq!(options.color, None);
+        assert!(options.vars.is_empty());
+        assert!(options.var_files.is_empty());
+        assert_eq!(options.parallel_builds, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_only_and_except() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        let options = BuildOptionsBuilder::default()
+            .only(vec![
+                "amazon-ebs.base".to_string(),
+                "docker.app".to_string(),
+            ])
+            .except(vec!["null.test".to_string()])
+            .build()
+            .unwrap();
+        packer.build("template.pkr.hcl", &options).unwrap();
+
+        let args = recorded_args(test_dir.path());
+        assert!(args.contains(&"-only=amazon-ebs.base,docker.app".to_string()));
+        assert!(args.contains(&"-except=null.test".to_string()));
+        assert_eq!(
+            args.iter().filter(|arg| arg.starts_with("-only")).count(),
+            1
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_on_error() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        packer
+            .build("template.pkr.hcl", &BuildOptions::default())
+            .unwrap();
+        let args = recorded_args(test_dir.path());
+        assert!(!args.iter().any(|arg| arg.starts_with("-on-error")));
+
+        let options = BuildOptionsBuilder::default()
+            .on_error(Some(OnError::Abort))
+            .build()
+            .unwrap();
+        packer.build("template.pkr.hcl", &options).unwrap();
+        let args = recorded_args(test_dir.path());
+        assert!(args.contains(&"-on-error=abort".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_options() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        let options = ValidateOptionsBuilder::default()
+            .syntax_only(true)
+            .vars(vec![("region".to_string(), "us-west-2".to_string())])
+            .var_files(vec![PathBuf::from("vars.pkrvars.hcl")])
+            .build()
+            .unwrap();
+        packer.validate("template.pkr.hcl", &options).unwrap();
+
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            vec![
+                "validate",
+                "-syntax-only",
+                "-var",
+                "region=us-west-2",
+                "-var-file=vars.pkrvars.hcl",
+                "template.pkr.hcl",
+            ]
+        );
+
+        packer.validate_default("template.pkr.hcl").unwrap();
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            vec!["validate", "template.pkr.hcl"]
+        );
+    }
+
+    #[test]
+    fn test_parse_warnings() {
+        let output = "\nWarning: Undefined -var variable\n\n  A \"region\" variable was passed in but was not found.\n\n\u{2502} Warning: Deprecated option\nThe configuration is valid.\n";
+        assert_eq!(
+            parse_warnings(output),
+            vec!["Undefined -var variable", "Deprecated option"]
+        );
+        assert!(parse_warnings("The configuration is valid.\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_undeclared_vars() {
+        let output = "Warning: Undefined -var variable\n\n  A \"region\" variable was passed in but was not found.\n\n\u{2502} Warning: Undefined variable\n\u{2502}\n\u{2502} The variable \"ami_name\" was set but was not declared as an input variable.\nWarning: Deprecated option\n  \"source_ami\" is deprecated\n";
+        assert_eq!(parse_undeclared_vars(output), ["region", "ami_name"]);
+        assert!(parse_undeclared_vars("The configuration is valid.\n").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_returns_warnings() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo 'Warning: Undefined -var variable' >&2\necho 'The configuration is valid.'",
+        ))
+        .unwrap();
+
+        let result = packer.validate_default("template.pkr.hcl").unwrap();
+        assert_eq!(result.warnings, vec!["Undefined -var variable"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_init_options() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        let options = InitOptions {
+            upgrade: true,
+            force: false,
+        };
+        packer.init("template.pkr.hcl", &options).unwrap();
+
+        let args = recorded_args(test_dir.path());
+        assert!(args.contains(&"-upgrade".to_string()));
+        assert!(!args.contains(&"-force".to_string()));
+
+        packer.init_default("template.pkr.hcl").unwrap();
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            vec!["init", "template.pkr.hcl"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_init_all() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "case \"$2\" in
+  aws.pkr.hcl) echo 'Installed plugin github.com/hashicorp/amazon v1.2.1 in \"/p/packer-plugin-amazon\"' ;;
+  broken*) echo 'Error: Unsupported block type' >&2; exit 1 ;;
+esac",
+        ))
+        .unwrap();
+
+        let results = packer
+            .init_all(&["aws.pkr.hcl", "docker.pkr.hcl"], &InitOptions::default())
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, PathBuf::from("aws.pkr.hcl"));
+        assert_eq!(results[0].1[0].source, "github.com/hashicorp/amazon");
+        assert_eq!(results[1], (PathBuf::from("docker.pkr.hcl"), Vec::new()));
+
+        match packer.init_all(
+            &["broken1.pkr.hcl", "aws.pkr.hcl", "broken2.pkr.hcl"],
+            &InitOptions::default(),
+        ) {
+            Err(PackerError::MultipleErrors(errors)) => {
+                let labels: Vec<&str> = errors.iter().map(|(label, _)| label.as_str()).collect();
+                assert_eq!(labels, ["broken1.pkr.hcl", "broken2.pkr.hcl"]);
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_init_output() {
+        let output = "Installed plugin github.com/hashicorp/amazon v1.2.1 in \"/home/ci/.config/packer/plugins/github.com/hashicorp/amazon/packer-plugin-amazon_v1.2.1_x5.0_linux_amd64\"\nInstalled plugin github.com/hashicorp/docker v1.0.8 in \"/p/packer-plugin-docker\"\n";
+        assert_eq!(
+            parse_init_output(output),
+            [
+                PluginInstallRecord {
+                    source: "github.com/hashicorp/amazon".to_string(),
+                    version: "1.2.1".to_string(),
+                    path: PathBuf::from("/home/ci/.config/packer/plugins/github.com/hashicorp/amazon/packer-plugin-amazon_v1.2.1_x5.0_linux_amd64"),
+                },
+                PluginInstallRecord {
+                    source: "github.com/hashicorp/docker".to_string(),
+                    version: "1.0.8".to_string(),
+                    path: PathBuf::from("/p/packer-plugin-docker"),
+                },
+            ]
+        );
+        assert!(parse_init_output("").is_empty());
+    }
+
+    #[test]
+    fn test_packer_version_parse() {
+        let version: PackerVersion = "Packer v1.10.3\n".parse().unwrap();
+        assert_eq!(version, PackerVersion::new(1, 10, 3));
+
+        let version: PackerVersion = "1.11.0-dev".parse().unwrap();
+        assert_eq!(version.pre.as_deref(), Some("dev"));
+        assert_eq!(version.to_string(), "1.11.0-dev");
+
+        assert!("Packer".parse::<PackerVersion>().is_err());
+        assert!("1.x.0".parse::<PackerVersion>().is_err());
+    }
+
+    #[test]
+    fn test_packer_version_ordering() {
+        let release = PackerVersion::new(1, 11, 0);
+        let pre: PackerVersion = "1.11.0-beta1".parse().unwrap();
+
+        assert!(PackerVersion::new(1, 9, 5) < PackerVersion::new(1, 10, 0));
+        assert!(PackerVersion::new(2, 0, 0) > PackerVersion::new(1, 99, 99));
+        assert!(pre < release);
+        assert!(pre > PackerVersion::new(1, 10, 9));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_require_version() {
+        let test_dir = setup_test_env();
+        let packer =
+            Packer::new_with_path(write_mock_packer(test_dir.path(), "echo 'Packer v1.7.8'"))
+                .unwrap();
+
+        assert!(packer.require_version(&PackerVersion::new(1, 7, 0)).is_ok());
+        match packer.require_version(&PackerVersion::new(1, 8, 0)) {
+            Err(PackerError::VersionError { found, required }) => {
+                assert_eq!(found, "1.7.8");
+                assert_eq!(required, "1.8.0");
+            }
+            other => panic!("expected VersionError, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_require_feature() {
+        assert!(PackerVersion::new(1, 7, 8).supports(PackerFeature::Fmt));
+        assert!(!PackerVersion::new(1, 8, 3).supports(PackerFeature::PluginsRequired));
+        assert!(!"1.8.4-dev"
+            .parse::<PackerVersion>()
+            .unwrap()
+            .supports(PackerFeature::PluginsRequired));
+
+        let test_dir = setup_test_env();
+        let calls = test_dir.path().join("calls.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!("echo \"$1\" >> '{}'\necho 'Packer v1.7.8'", calls.display()),
+        ))
+        .unwrap();
+
+        let stdout = SharedBuffer::default();
+        let packer = packer.with_stdout_writer(Box::new(stdout.clone()));
+
+        match packer.plugins().required("template.pkr.hcl") {
+            Err(PackerError::VersionError { found, required }) => {
+                assert_eq!(found, "1.7.8");
+                assert_eq!(required, "1.8.4");
+            }
+            other => panic!("expected VersionError, got {:?}", other),
+        }
+        packer.require_feature(PackerFeature::Fmt).unwrap();
+        // Probed once, without the banner reaching the user's writer
+        assert_eq!(std::fs::read_to_string(&calls).unwrap(), "version\n");
+        assert_eq!(stdout.contents(), "");
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = PackerError::VersionError {
+            found: "1.7.8".to_string(),
+            required: "1.8.0".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Packer 1.7.8 is older than the required version 1.8.0"
+        );
+
+        let err = PackerError::TimeoutError {
+            elapsed: std::time::Duration::from_secs(30),
+        };
+        assert_eq!(err.to_string(), "Packer command timed out after 30s");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_command_capture() {
+        let test_dir = setup_test_env();
+        let packer =
+            Packer::new_with_path(write_mock_packer(test_dir.path(), "echo out\necho err >&2"))
+                .unwrap();
+
+        let output = packer
+            .execute_command_capture(packer.base_command())
+            .unwrap();
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+        assert_eq!(output.exit_code, 0);
+
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo broken >&2\nexit 1",
+        ))
+        .unwrap();
+        match packer.execute_command_capture(packer.base_command()) {
+            Err(PackerError::ExecutionError {
+                message,
+                exit_code,
+                stderr,
+            }) => {
+                assert_eq!(message, "broken");
+                assert_eq!(exit_code, Some(1));
+                assert_eq!(stderr, "broken\n");
+            }
+            other => panic!("expected ExecutionError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_artifacts() {
+        let output = "\
+1700000000,,ui,say,==> Builds finished.
+1700000000,amazon-ebs.base,artifact-count,1
+1700000000,amazon-ebs.base,artifact,0,builder-id,mitchellh.amazonebs
+1700000000,amazon-ebs.base,artifact,0,id,us-east-1:ami-0123
+1700000000,amazon-ebs.base,artifact,0,string,AMIs were created%!(PACKER_COMMA) see above
+1700000000,amazon-ebs.base,artifact,0,files-count,0
+1700000000,amazon-ebs.base,artifact,0,end
+1700000000,file.out,artifact,0,builder-id,packer.file
+1700000000,file.out,artifact,0,id,out.txt
+1700000000,file.out,artifact,0,file,0,out.txt
+";
+        let artifacts = parse_artifacts(output);
+
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(artifacts[0].builder_name, "amazon-ebs.base");
+        assert_eq!(artifacts[0].artifact_type, "mitchellh.amazonebs");
+        assert_eq!(artifacts[0].artifact_id, "us-east-1:ami-0123");
+        assert!(artifacts[0].files.is_empty());
+        assert_eq!(artifacts[1].files, vec![PathBuf::from("out.txt")]);
+    }
+
+    #[test]
+    fn test_parse_machine_readable() {
+        let output = "\
+1700000000,,version,1.10.3
+1700000001,docker.app,ui,say,line one\\nline two%!(PACKER_COMMA) still line two
+not a machine-readable line
+1700000002,docker.app,error,something broke
+";
+        let events = parse_machine_readable(output);
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].timestamp, 1700000000);
+        assert_eq!(events[0].target, "");
+        assert_eq!(events[0].kind(), MachineReadableEventKind::Version);
+        assert_eq!(events[1].kind(), MachineReadableEventKind::Ui);
+        assert_eq!(
+            events[1].data,
+            vec!["say", "line one\nline two, still line two"]
+        );
+        assert_eq!(events[2].kind(), MachineReadableEventKind::Error);
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_build_async_propagates_errors() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo 'Build failed' >&2\nexit 1",
+        ))
+        .unwrap();
+
+        match packer
+            .build_async("template.pkr.hcl", &BuildOptions::default())
+            .await
+        {
+            Err(PackerError::ExecutionError { stderr, .. }) => assert_eq!(stderr, "Build failed\n"),
+            other => panic!("expected ExecutionError, got {:?}", other),
+        }
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_build_stream() {
+        use tokio_stream::StreamExt;
+
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo '1700000000,docker.app,ui,say,Starting'\necho 'Provisioning' >&2\nexit 3",
+        ))
+        .unwrap();
+
+        let stream = packer.build_stream("template.pkr.hcl", &BuildOptions::default());
+        tokio::pin!(stream);
+        let mut lines = Vec::new();
+        while let Some(line) = stream.next().await {
+            lines.push(line.unwrap());
+        }
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines.contains(&OutputLine::Stdout(
+            "1700000000,docker.app,ui,say,Starting".to_string()
+        )));
+        assert!(lines.contains(&OutputLine::Stderr("Provisioning".to_string())));
+        match lines.last() {
+            Some(OutputLine::ExitStatus(status)) => assert_eq!(status.code(), Some(3)),
+            other => panic!("expected ExitStatus, got {:?}", other),
+        }
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_build_cancellable() {
+        let test_dir = setup_test_env();
+        let pid_file = test_dir.path().join("pid");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!("echo $$ > '{}'\nsleep 30", pid_file.display()),
+        ))
+        .unwrap();
+
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let pid_to_wait_for = pid_file.clone();
+        std::thread::spawn(move || {
+            while !pid_to_wait_for.exists() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let result = packer
+            .build_cancellable("template.pkr.hcl", &BuildOptions::default(), token)
+            .await;
+        assert!(
+            matches!(result, Err(PackerError::Cancelled)),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let pid: libc::pid_t = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        // SAFETY: signal 0 only checks whether the process exists.
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_validate_async() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo 'Warning: Undefined -var variable'",
+        ))
+        .unwrap();
+
+        let result = packer
+            .validate_async("template.pkr.hcl", &ValidateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.warnings, vec!["Undefined -var variable"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_with_output() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo '1700000000,docker.app,artifact,0,id,sha256:abc'\necho 'provisioning' >&2",
+        ))
+        .unwrap();
+
+        let mut lines = Vec::new();
+        let result = packer
+            .build_with_output("template.pkr.hcl", &BuildOptions::default(), |line| {
+                lines.push(line)
+            })
+            .unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines.contains(&OutputLine::Stdout(
+            "1700000000,docker.app,artifact,0,id,sha256:abc".to_string()
+        )));
+        assert!(lines.contains(&OutputLine::Stderr("provisioning".to_string())));
+        assert_eq!(result.artifacts[0].artifact_id, "sha256:abc");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_with_output_failure() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo 'Build failed' >&2\nexit 1",
+        ))
+        .unwrap();
+
+        let result = packer.build_with_output("template.pkr.hcl", &BuildOptions::default(), |_| {});
+        assert!(matches!(result, Err(PackerError::ExecutionError { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_with_timeout() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(test_dir.path(), "sleep 10")).unwrap();
+
+        let started = Instant::now();
+        let result = packer.build_with_timeout(
+            "template.pkr.hcl",
+            &BuildOptions::default(),
+            Duration::from_millis(200),
+        );
+
+        match result {
+            Err(PackerError::TimeoutError { elapsed }) => {
+                assert_eq!(elapsed, Duration::from_millis(200))
+            }
+            other => panic!("expected TimeoutError, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo '1700000000,file.out,artifact,0,id,out.txt'",
+        ))
+        .unwrap();
+        let result = packer
+            .build_with_timeout(
+                "template.pkr.hcl",
+                &BuildOptions::default(),
+                Duration::from_secs(30),
+            )
+            .unwrap();
+        assert_eq!(result.artifacts[0].artifact_id, "out.txt");
+    }
+
+    #[test]
+    fn test_parse_fmt_files() {
+        let output = "\
+base.pkr.hcl
+--- old/base.pkr.hcl
++++ new/base.pkr.hcl
+@@ -1,3 +1,3 @@
+ source \"null\" \"test\" {
+-  communicator=\"none\"
++  communicator = \"none\"
+ }
+vars/dev.pkrvars.hcl
+";
+        assert_eq!(
+            parse_fmt_files(output),
+            vec![
+                PathBuf::from("base.pkr.hcl"),
+                PathBuf::from("vars/dev.pkrvars.hcl")
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fmt() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            "echo base.pkr.hcl\necho '--- old/base.pkr.hcl'\necho '+++ new/base.pkr.hcl'",
+        ))
+        .unwrap();
+
+        let result = packer.fmt(".", &FmtOptions::default()).unwrap();
+        assert_eq!(result.changed_files, vec![PathBuf::from("base.pkr.hcl")]);
+        assert_eq!(result.diff, None);
+
+        let options = FmtOptions {
+            diff: true,
+            ..Default::default()
+        };
+        let result = packer.fmt(".", &options).unwrap();
+        assert_eq!(result.changed_files, vec![PathBuf::from("base.pkr.hcl")]);
+        assert_eq!(
+            result.diff.as_deref(),
+            Some("--- old/base.pkr.hcl\n+++ new/base.pkr.hcl\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fmt_check_failure() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            "echo base.pkr.hcl\nexit 3",
+        ))
+        .unwrap();
+
+        let options = FmtOptions {
+            check: true,
+            ..Default::default()
+        };
+        match packer.fmt(".", &options) {
+            Err(PackerError::FmtCheckFailed { files }) => {
+                assert_eq!(files, vec![PathBuf::from("base.pkr.hcl")])
+            }
+            other => panic!("expected FmtCheckFailed, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fmt_files() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            "for file; do :; done
+case \"$file\" in
+  ugly*) echo \"$file\"; [ \"$2\" = -check ] && exit 3 ;;
+esac
+exit 0",
+        ))
+        .unwrap();
+        let files = [
+            "base.pkr.hcl",
+            "ugly.pkr.hcl",
+            "ugly.pkr.hcl",
+            "ugly2.pkr.hcl",
+        ];
+
+        let result = packer.fmt_files(&files, &FmtOptions::default()).unwrap();
+        assert_eq!(
+            result.changed_files,
+            vec![
+                PathBuf::from("ugly.pkr.hcl"),
+                PathBuf::from("ugly2.pkr.hcl")
+            ]
+        );
+
+        let options = FmtOptions {
+            check: true,
+            ..Default::default()
+        };
+        match packer.fmt_files(&files, &options) {
+            Err(PackerError::FmtCheckFailed { files }) => assert_eq!(
+                files,
+                vec![
+                    PathBuf::from("ugly.pkr.hcl"),
+                    PathBuf::from("ugly2.pkr.hcl")
+                ]
+            ),
+            other => panic!("expected FmtCheckFailed, got {:?}", other),
+        }
+        assert!(packer.fmt_files(&files[..1], &options).is_ok());
+    }
+
+    #[test]
+    fn test_parse_plugin_list() {
+        let output = "\
+github.com/hashicorp/docker v1.0.8
+/home/ci/.config/packer/plugins/github.com/hashicorp/amazon/packer-plugin-amazon_v1.2.1_x5.0_linux_amd64
+/opt/packer-plugin-qemu_v1.0.9_x5.0_linux_amd64
+";
+        let plugins = parse_plugin_list(output);
+
+        assert_eq!(plugins.len(), 3);
+        assert_eq!(plugins[0].name, "github.com/hashicorp/docker");
+        assert_eq!(plugins[0].version, "1.0.8");
+        assert_eq!(plugins[1].name, "github.com/hashicorp/amazon");
+        assert_eq!(plugins[1].version, "1.2.1");
+        assert_eq!(
+            plugins[1].path,
+            PathBuf::from("/home/ci/.config/packer/plugins/github.com/hashicorp/amazon/packer-plugin-amazon_v1.2.1_x5.0_linux_amd64")
+        );
+        assert_eq!(plugins[2].name, "qemu");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[allow(deprecated)]
+    fn test_has_plugin() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "echo 'github.com/hashicorp/amazon v1.2.1'",
+        ))
+        .unwrap();
+
+        assert!(packer.has_plugin("github.com/hashicorp/amazon").unwrap());
+        assert!(packer.has_plugin("amazon").unwrap());
+        assert!(!packer.has_plugin("docker").unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[allow(deprecated)]
+    fn test_plugin_upgrade_all() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "case \"$2\" in
+  list) printf 'github.com/hashicorp/amazon v1.2.1\\ngithub.com/hashicorp/docker v1.0.8\\n' ;;
+  upgrade) [ \"$3\" = github.com/hashicorp/amazon ] ;;
+esac",
+        ))
+        .unwrap();
+
+        match packer.plugin_upgrade_all() {
+            Err(PackerError::MultipleErrors(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].0, "github.com/hashicorp/docker");
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        assert!(PackerError::from_errors(Vec::new()).is_ok());
+
+        let err = PackerError::from_errors(vec![
+            ("amazon".to_string(), PackerError::not_found(Vec::new())),
+            (
+                "docker".to_string(),
+                PackerError::ConfigError("bad".to_string()),
+            ),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 of the operations failed\n  amazon: Failed to find Packer executable\n  docker: Invalid configuration: bad"
+        );
+
+        assert!(PackerError::from_errors(vec![(
+            "plugins".to_string(),
+            PackerError::MultipleErrors(Vec::new())
+        )])
+        .is_ok());
+
+        let err = PackerError::from_errors(vec![
+            (
+                "plugins".to_string(),
+                PackerError::MultipleErrors(vec![
+                    ("amazon".to_string(), PackerError::not_found(Vec::new())),
+                    ("docker".to_string(), PackerError::not_found(Vec::new())),
+                ]),
+            ),
+            ("build".to_string(), PackerError::not_found(Vec::new())),
+        ])
+        .unwrap_err();
+        match err {
+            PackerError::MultipleErrors(errors) => {
+                let labels: Vec<_> = errors.iter().map(|(label, _)| label.as_str()).collect();
+                assert_eq!(labels, vec!["plugins: amazon", "plugins: docker", "build"]);
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fmt_check() {
+        let test_dir = setup_test_env();
+        let bin_dir = setup_test_env();
+        // Stands in for `packer fmt -check <dir>`: tight `=` signs count as unformatted
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            bin_dir.path(),
+            "[ -d \"$3\" ] || { echo 'Error: no such directory' >&2; exit 1; }
+grep -l '[a-z]=\"' \"$3\"/*.pkr.hcl && exit 3
+exit 0",
+        ))
+        .unwrap();
+
+        let template = test_dir.path().join("base.pkr.hcl");
+        std::fs::write(
+            &template,
+            "source \"null\" \"test\" {\n  communicator = \"none\"\n}\n",
+        )
+        .unwrap();
+        assert!(packer.fmt_check(test_dir.path()).unwrap());
+
+        std::fs::write(
+            &template,
+            "source \"null\" \"test\" {\n  communicator=\"none\"\n}\n",
+        )
+        .unwrap();
+        assert!(!packer.fmt_check(test_dir.path()).unwrap());
+
+        assert!(packer.fmt_check(test_dir.path().join("missing")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hcl2_upgrade_output_file() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            "echo 'converted' > \"${2#-output-file=}\"\necho 'Successfully created file'",
+        ))
+        .unwrap()
+        .with_working_dir(test_dir.path());
+
+        let options = Hcl2UpgradeOptionsBuilder::default()
+            .output_file(Some(PathBuf::from("template.pkr.hcl")))
+            .build()
+            .unwrap();
+        let converted = packer.hcl2_upgrade("template.json", &options).unwrap();
+        assert_eq!(converted, "converted");
+
+        let converted = packer
+            .hcl2_upgrade("template.json", &Hcl2UpgradeOptions::default())
+            .unwrap();
+        assert_eq!(converted, "Successfully created file");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_build_options_serde_round_trip() {
+        let options = BuildOptions {
+            parallel_builds: Some(2),
+            debug: true,
+            force: true,
+            timestamp_ui: true,
+            color: Some(false),
+            vars: vec![
+                ("region".to_string(), "us-west-2".into()),
+                ("instance_type".to_string(), "t3.micro".into()),
+                ("api_key".to_string(), SecretVar::new("hunter2").into()),
+            ],
+            var_files: vec![PathBuf::from("vars/dev.pkrvars.hcl")],
+            only: vec!["amazon-ebs.base".to_string()],
+            except: vec!["null.test".to_string()],
+            on_error: Some(OnError::RunCleanup),
+            machine_readable_log_file: Some(PathBuf::from("build.log")),
+            disable_checkpoint: false,
+            isolated_env: true,
+            env_whitelist: vec!["AWS_PROFILE".to_string()],
+        };
+
+        let json = serde_json::to_string(&options).unwrap();
+        assert!(json.contains(
+            r#""vars":{"region":"us-west-2","instance_type":"t3.micro","api_key":{"secret":"hunter2"}}"#
+        ));
+        assert!(json.contains(r#""on_error":"run-cleanup""#));
+
+        let round_tripped: BuildOptions = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_tripped, options);
+
+        let partial: BuildOptions = serde_json::from_str(r#"{"debug": true}"#).unwrap();
+        assert!(partial.debug);
+        assert_eq!(partial.color, None);
+        assert!(partial.disable_checkpoint);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_errors_are_config_errors() {
+        fn from_json(json: &str) -> Result<BuildOptions> {
+            Ok(serde_json::from_str(json)?)
+        }
+        fn from_toml(toml: &str) -> Result<BuildOptions> {
+            Ok(toml::from_str(toml)?)
+        }
+
+        match from_json(r#"{"debug": "#) {
+            Err(PackerError::ConfigError(message)) => assert!(message.contains("EOF")),
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+        match from_toml("debug = ") {
+            Err(PackerError::ConfigError(_)) => {}
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_build_options_file_round_trip() {
+        let test_dir = setup_test_env();
+        let options = BuildOptions {
+            parallel_builds: Some(4),
+            force: true,
+            vars: vec![("region".to_string(), "eu-west-1".into())],
+            var_files: vec![PathBuf::from("common.pkrvars.hcl")],
+            only: vec!["docker.app".to_string()],
+            on_error: Some(OnError::Abort),
+            ..Default::default()
+        };
+
+        for name in ["options.json", "options.toml"] {
+            let path = test_dir.path().join(name);
+            options.to_file(&path).unwrap();
+            assert_eq!(BuildOptions::from_file(&path).unwrap(), options);
+        }
+
+        assert!(matches!(
+            options.to_file(test_dir.path().join("options.yaml")),
+            Err(PackerError::ConfigError(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_build_options_from_file_parse_error() {
+        let test_dir = setup_test_env();
+        let path = test_dir.path().join("options.toml");
+        std::fs::write(&path, "force = true\ndebug = \"yes\"\n").unwrap();
+
+        match BuildOptions::from_file(&path) {
+            Err(PackerError::ConfigError(message)) => {
+                assert!(
+                    message.starts_with(&format!("{}:2:", path.display())),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_inspection_hcl2() {
+        let output = "\
+Packer Inspect: HCL2 mode
+
+> input-variables:
+
+var.region: \"us-east-1\"
+
+> local-variables:
+
+
+> builds:
+
+  > <unnamed build 0>:
+
+    sources:
+
+      amazon-ebs.base
+      docker.app
+
+    provisioners:
+
+      shell
+
+    post-processors:
+
+      0:
+        manifest
+
+";
+        let inspection = parse_inspection(output);
+
+        assert_eq!(
+            inspection.builders,
+            vec![
+                BuilderInfo {
+                    type_name: "amazon-ebs".to_string(),
+                    name: "base".to_string()
+                },
+                BuilderInfo {
+                    type_name: "docker".to_string(),
+                    name: "app".to_string()
+                },
+            ]
+        );
+        assert_eq!(inspection.provisioners.len(), 1);
+        assert_eq!(inspection.provisioners[0].type_name, "shell");
+        assert_eq!(inspection.post_processors.len(), 1);
+        assert_eq!(inspection.post_processors[0].type_name, "manifest");
+    }
+
+    #[test]
+    fn test_parse_inspection_legacy() {
+        let output = "\
+Optional variables and their defaults:
+
+  aws_region = us-east-1
+
+Builders:
+
+  amazon-ebs
+  app (docker)
+
+Provisioners:
+
+  shell
+
+Note: If your build names contain user variables or template
+functions such as 'timestamp', these are processed at build time,
+and therefore only show in their raw form here.
+";
+        let inspection = parse_inspection(output);
+
+        assert_eq!(inspection.builders.len(), 2);
+        assert_eq!(inspection.builders[0].type_name, "amazon-ebs");
+        assert_eq!(inspection.builders[0].name, "amazon-ebs");
+        assert_eq!(inspection.builders[1].type_name, "docker");
+        assert_eq!(inspection.builders[1].name, "app");
+        assert_eq!(inspection.provisioners.len(), 1);
+        assert!(inspection.post_processors.is_empty());
+    }
+
+    #[test]
+    fn test_parse_plugin_requirements() {
+        let output = "github.com/hashicorp/amazon >= 1.2.0, < 2.0.0\ngithub.com/hashicorp/docker\n";
+        assert_eq!(
+            parse_plugin_requirements(output),
+            vec![
+                PluginRequirement {
+                    source: "github.com/hashicorp/amazon".to_string(),
+                    version_constraint: ">= 1.2.0, < 2.0.0".to_string(),
+                },
+                PluginRequirement {
+                    source: "github.com/hashicorp/docker".to_string(),
+                    version_constraint: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[allow(deprecated)]
+    fn test_install_required_plugins() {
+        let test_dir = setup_test_env();
+        let log = test_dir.path().join("installs.txt");
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            &format!(
+                "case \"$2\" in
+  list) echo 'github.com/hashicorp/amazon v1.2.1' ;;
+  required) printf 'github.com/hashicorp/amazon >= 1.0.0\\ngithub.com/hashicorp/docker >= 1.0.0\\n' ;;
+  install) echo \"$3\" >> '{}' ;;
+esac",
+                log.display()
+            ),
+        ))
+        .unwrap();
+
+        packer.install_required_plugins("template.pkr.hcl").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "github.com/hashicorp/docker\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plugin_manager() {
+        let test_dir = setup_test_env();
+        let log = test_dir.path().join("calls.txt");
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            &format!(
+                "case \"$2\" in
+  list) echo 'github.com/hashicorp/amazon v1.2.1' ;;
+  required) printf 'github.com/hashicorp/amazon >= 1.0.0\\ngithub.com/hashicorp/docker >= 1.0.0\\n' ;;
+  *) echo \"$2 $3\" >> '{}' ;;
+esac",
+                log.display()
+            ),
+        ))
+        .unwrap();
+        let plugins = packer.plugins();
+
+        assert!(plugins.is_installed("amazon").unwrap());
+        assert_eq!(plugins.list_parsed().unwrap()[0].version, "1.2.1");
+        assert_eq!(plugins.required("template.pkr.hcl").unwrap().len(), 2);
+
+        let missing = plugins.check_required("template.pkr.hcl").unwrap();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].source, "github.com/hashicorp/docker");
+
+        plugins.install_required("template.pkr.hcl").unwrap();
+        plugins.upgrade("github.com/hashicorp/amazon").unwrap();
+        assert_eq!(plugins.remove("github.com/hashicorp/amazon").unwrap(), None);
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "install github.com/hashicorp/docker\n\
+             upgrade github.com/hashicorp/amazon\n\
+             remove github.com/hashicorp/amazon\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plugin_remove() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "case \"$3\" in
+  github.com/hashicorp/docker) echo '/plugins/github.com/hashicorp/docker/packer-plugin-docker_v1.0.8_x5.0_linux_amd64' ;;
+  github.com/hashicorp/amazon) echo 'No installed plugins found' ;;
+  *) echo 'Error: No installed plugin found matching the plugin constraints' >&2; exit 1 ;;
+esac",
+        ))
+        .unwrap();
+        let plugins = packer.plugins();
+
+        assert_eq!(
+            plugins.remove("github.com/hashicorp/docker").unwrap(),
+            Some(PathBuf::from(
+                "/plugins/github.com/hashicorp/docker/packer-plugin-docker_v1.0.8_x5.0_linux_amd64"
+            ))
+        );
+        assert_eq!(plugins.remove("github.com/hashicorp/amazon").unwrap(), None);
+        match plugins.remove("github.com/hashicorp/vagrant") {
+            Err(PackerError::PluginNotFound(name)) => {
+                assert_eq!(name, "github.com/hashicorp/vagrant")
+            }
+            other => panic!("expected PluginNotFound, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plugin_install_version() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+        let plugins = packer.plugins();
+
+        plugins
+            .install_version("github.com/hashicorp/amazon", "v1.2.1")
+            .unwrap();
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            ["plugin", "install", "github.com/hashicorp/amazon", "v1.2.1"]
+        );
+
+        plugins.install("github.com/hashicorp/docker").unwrap();
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            ["plugin", "install", "github.com/hashicorp/docker"]
+        );
+
+        std::fs::remove_file(test_dir.path().join("args.txt")).unwrap();
+        assert!(matches!(
+            plugins.install_version("github.com/hashicorp/amazon", " "),
+            Err(PackerError::ConfigError(_))
+        ));
+        assert!(!test_dir.path().join("args.txt").exists());
+    }
+
+    #[test]
+    fn test_build_options_add_vars() {
+        let mut options = BuildOptionsBuilder::default()
+            .vars(vec![("region", "us-west-2")])
+            .var_files(vec![PathBuf::from("base.pkrvars.hcl")])
+            .build()
+            .unwrap();
+
+        options
+            .add_var("instance_type", "t3.micro")
+            .add_vars(vec![("ami_name".to_string(), "web".to_string())])
+            .add_var_file("prod.pkrvars.hcl");
+
+        let keys: Vec<_> = options.vars.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["region", "instance_type", "ami_name"]);
+        assert_eq!(options.vars[1].1, "t3.micro");
+        assert_eq!(
+            options.var_files,
+            [
+                PathBuf::from("base.pkrvars.hcl"),
+                PathBuf::from("prod.pkrvars.hcl")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_options_merge() {
+        let mut base = BuildOptionsBuilder::default()
+            .parallel_builds(Some(2))
+            .color(true)
+            .on_error(Some(OnError::Cleanup))
+            .only(vec!["amazon-ebs.base".to_string()])
+            .build()
+            .unwrap();
+        base.add_var("region", "us-west-2")
+            .add_var_file("base.pkrvars.hcl");
+
+        let mut job = BuildOptionsBuilder::default()
+            .force(true)
+            .color(false)
+            .only(vec!["docker.app".to_string()])
+            .build()
+            .unwrap();
+        job.add_var("region", "eu-west-1").add_var("tag", "nightly");
+
+        let merged = base.merge(job);
+        assert_eq!(merged.parallel_builds, Some(2));
+        assert!(merged.force);
+        assert_eq!(merged.color, Some(false));
+        assert_eq!(merged.on_error, Some(OnError::Cleanup));
+        assert_eq!(merged.only, ["amazon-ebs.base", "docker.app"]);
+        assert_eq!(merged.var_files, [PathBuf::from("base.pkrvars.hcl")]);
+
+        let vars: Vec<_> = merged
+            .vars
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.expose()))
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                ("region", "us-west-2"),
+                ("region", "eu-west-1"),
+                ("tag", "nightly")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_options_from_env() {
+        let _guard = lock_env();
+        std::env::set_var("PKR_VAR_zone", "b");
+        std::env::set_var("PKR_VAR_region", "us-west-2");
+
+        let options = BuildOptions::from_env();
+        std::env::remove_var("PKR_VAR_zone");
+        std::env::remove_var("PKR_VAR_region");
+
+        let vars: Vec<_> = options
+            .vars
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.expose()))
+            .collect();
+        assert_eq!(vars, [("region", "us-west-2"), ("zone", "b")]);
+        assert_eq!(options.color, None);
+
+        assert!(BuildOptions::from_env().vars.is_empty());
+    }
+
+    /// A `Write` sink tests can read back after handing it to a `Packer`
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_custom_output_writers() {
+        let test_dir = setup_test_env();
+        let path = write_mock_packer(test_dir.path(), "echo out-$1; echo err-$1 >&2");
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let packer = Packer::new_with_path(path)
+            .unwrap()
+            .with_stdout_writer(Box::new(stdout.clone()))
+            .with_stderr_writer(Box::new(stderr.clone()));
+
+        packer.init_default("template.pkr.hcl").unwrap();
+        assert_eq!(packer.version().unwrap(), "out-version");
+
+        assert_eq!(stdout.contents(), "out-init\nout-version\n");
+        assert_eq!(stderr.contents(), "err-init\nerr-version\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execution_error_exit_code() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(test_dir.path(), "exit 2")).unwrap();
+
+        let err = packer.init_default("template.pkr.hcl").unwrap_err();
+        assert_eq!(err.exit_code(), Some(2));
+        assert!(matches!(
+            err,
+            PackerError::ExecutionError { exit_code: Some(2), ref stderr, .. } if stderr.is_empty()
+        ));
+
+        let err = packer.inspect("template.pkr.hcl").unwrap_err();
+        assert_eq!(err.exit_code(), Some(2));
+        assert_eq!(PackerError::not_found(Vec::new()).exit_code(), None);
+    }
+
+    #[cfg(all(unix, feature = "tempfile"))]
+    #[test]
+    fn test_with_temp_working_dir() {
+        let test_dir = setup_test_env();
+        let (packer, working_dir) =
+            Packer::new_with_path(write_mock_packer(test_dir.path(), "pwd >&2"))
+                .unwrap()
+                .with_temp_working_dir()
+                .unwrap();
+
+        let result = packer
+            .build("template.pkr.hcl", &BuildOptions::default())
+            .unwrap();
+        assert_eq!(
+            PathBuf::from(result.provisioner_log.trim())
+                .canonicalize()
+                .unwrap(),
+            working_dir.path().canonicalize().unwrap()
+        );
+
+        drop(working_dir);
+        match packer.build("template.pkr.hcl", &BuildOptions::default()) {
+            Err(PackerError::IoError(_)) => {}
+            other => panic!("expected IoError, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_dry_run() {
+        let test_dir = setup_test_env();
+        let calls = test_dir.path().join("calls.txt");
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            &format!(
+                "echo \"$*\" >> '{}'
+case \"$1 $2\" in
+  inspect*) printf 'Builders:\\n  docker.app\\n' ;;
+  'plugins required') echo 'github.com/hashicorp/docker >= 1.0.0' ;;
+  'plugin list') echo 'github.com/hashicorp/amazon v1.2.1' ;;
+esac",
+                calls.display()
+            ),
+        ))
+        .unwrap();
+
+        let mut options = BuildOptions::default();
+        options.add_secret_var("api_key", "hunter2");
+        let result = packer.build_dry_run("template.pkr.hcl", &options).unwrap();
+        assert_eq!(result.validation, ValidationResult::default());
+        assert_eq!(result.inspection.builders.len(), 1);
+        assert_eq!(
+            result.missing_plugins[0].source,
+            "github.com/hashicorp/docker"
+        );
+
+        let calls = std::fs::read_to_string(&calls).unwrap();
+        assert!(calls.contains("validate -var api_key=hunter2 template.pkr.hcl\n"));
+        assert!(calls.contains("inspect -var api_key=hunter2 template.pkr.hcl\n"));
+        assert!(!calls.contains("build"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_isolated_env() {
+        let _guard = lock_env();
+        let test_dir = setup_test_env();
+        let env_file = test_dir.path().join("env.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!("env > '{}'", env_file.display()),
+        ))
+        .unwrap()
+        .with_env("PACKER_LOG", "1");
+        std::env::set_var("PACKER_RS_TEST_TOKEN", "secret");
+        std::env::set_var("PACKER_RS_TEST_PROFILE", "ci");
+
+        let env = |options: &BuildOptions| {
+            packer.build("template.pkr.hcl", options).unwrap();
+            std::fs::read_to_string(&env_file).unwrap()
+        };
+
+        let inherited = env(&BuildOptions::default());
+        assert!(inherited.contains("PACKER_RS_TEST_TOKEN=secret\n"));
+
+        let options = BuildOptions {
+            isolated_env: true,
+            env_whitelist: vec!["PACKER_RS_TEST_PROFILE".to_string()],
+            ..BuildOptions::default()
+        };
+        let isolated = env(&options);
+        std::env::remove_var("PACKER_RS_TEST_TOKEN");
+        std::env::remove_var("PACKER_RS_TEST_PROFILE");
+
+        assert!(!isolated.contains("PACKER_RS_TEST_TOKEN"));
+        assert!(isolated.contains("PACKER_RS_TEST_PROFILE=ci\n"));
+        assert!(isolated.contains("PACKER_LOG=1\n"));
+        assert!(isolated.contains("CHECKPOINT_DISABLE=1\n"));
+        assert!(isolated.contains(&format!("PATH={}\n", std::env::var("PATH").unwrap())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_disables_checkpoint() {
+        let test_dir = setup_test_env();
+        let env_file = test_dir.path().join("env.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!(
+                "echo \"${{CHECKPOINT_DISABLE:-unset}}\" > '{}'",
+                env_file.display()
+            ),
+        ))
+        .unwrap();
+
+        let options = BuildOptionsBuilder::default().build().unwrap();
+        assert!(options.disable_checkpoint);
+        packer.build("template.pkr.hcl", &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&env_file).unwrap(), "1\n");
+
+        let options = BuildOptions {
+            disable_checkpoint: false,
+            ..BuildOptions::default()
+        };
+        packer.build("template.pkr.hcl", &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&env_file).unwrap(), "unset\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_only_and_except_shorthands() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        packer
+            .build_only("template.pkr.hcl", &["amazon-ebs.ami", "docker.app"])
+            .unwrap();
+        let args = recorded_args(test_dir.path());
+        assert!(args.contains(&"-only=amazon-ebs.ami,docker.app".to_string()));
+        assert!(!args.iter().any(|arg| arg.starts_with("-except")));
+
+        packer
+            .build_except("template.pkr.hcl", &["docker.app"])
+            .unwrap();
+        let args = recorded_args(test_dir.path());
+        assert!(args.contains(&"-except=docker.app".to_string()));
+        assert!(!args.iter().any(|arg| arg.starts_with("-only")));
+
+        let options =
+            BuildOptions::excluding(["amazon-ebs.base", "docker.app"]).with_vars([("env", "dev")]);
+        assert_eq!(options.except, vec!["amazon-ebs.base", "docker.app"]);
+        assert_eq!(options.vars, vec![("env".to_string(), "dev".into())]);
+        assert_eq!(options.on_error, BuildOptions::default().on_error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_console_eval() {
+        let test_dir = setup_test_env();
+        let input = test_dir.path().join("stdin.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!("tee '{}' | sed 's/^/=> /'", input.display()),
+        ))
+        .unwrap();
+
+        let result = packer
+            .console_eval(
+                "template.pkr.hcl",
+                "var.region\nupper(\"a\")",
+                &ConsoleOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(result, "=> var.region\n=> upper(\"a\")");
+        assert_eq!(
+            std::fs::read_to_string(input).unwrap(),
+            "var.region\nupper(\"a\")\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_console_options() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        let options = ConsoleOptions::default()
+            .with_vars([("region", "us-west-2")])
+            .with_var_files(["prod.pkrvars.hcl"]);
+        packer.console(Some("template.pkr.hcl"), &options).unwrap();
+        assert_eq!(
+            recorded_args(test_dir.path()),
+            [
+                "console",
+                "-var",
+                "region=us-west-2",
+                "-var-file=prod.pkrvars.hcl",
+                "template.pkr.hcl"
+            ]
+        );
+
+        packer
+            .console(None::<&Path>, &ConsoleOptions::default())
+            .unwrap();
+        assert_eq!(recorded_args(test_dir.path()), ["console"]);
+
+        packer
+            .console_eval("template.pkr.hcl", "var.region", &options)
+            .unwrap();
+        assert!(recorded_args(test_dir.path()).contains(&"region=us-west-2".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_console_eval_timeout() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(test_dir.path(), "sleep 5")).unwrap();
+
+        let options = ConsoleOptionsBuilder::default()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let result = packer.console_eval("template.pkr.hcl", "1 + 1", &options);
+
+        assert!(matches!(result, Err(PackerError::TimeoutError { .. })));
+        assert!(started.elapsed() < Duration::from_secs(4));
+
+        // Far more input than a pipe buffer holds, which the console never reads
+        let expression = "x".repeat(4 << 20);
+        let started = Instant::now();
+        let result = packer.console_eval("template.pkr.hcl", &expression, &options);
+
+        assert!(matches!(result, Err(PackerError::TimeoutError { .. })));
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_packer_release_from_env() {
+        let _guard = lock_env();
+        std::env::remove_var("PACKER_BUILD_VERSION");
+        std::env::remove_var("PACKER_DOWNLOAD_BASE_URL");
+        assert_eq!(
+            packer_release().unwrap(),
+            (PACKER_VERSION.to_string(), PACKER_RELEASES_URL.to_string())
+        );
+
+        std::env::set_var("PACKER_BUILD_VERSION", "v1.10.3");
+        std::env::set_var(
+            "PACKER_DOWNLOAD_BASE_URL",
+            "https://mirror.example.com/packer/",
+        );
+        let release = packer_release();
+
+        std::env::set_var(
+            "PACKER_DOWNLOAD_BASE_URL",
+            "http://mirror.example.com/packer",
+        );
+        let insecure = packer_release();
+
+        std::env::set_var(
+            "PACKER_DOWNLOAD_BASE_URL",
+            "https://mirror.example.com/packer",
+        );
+        std::env::set_var("PACKER_BUILD_VERSION", "latest");
+        let bad_version = packer_release();
+
+        std::env::remove_var("PACKER_BUILD_VERSION");
+        std::env::remove_var("PACKER_DOWNLOAD_BASE_URL");
+
+        assert_eq!(
+            release.unwrap(),
+            (
+                "1.10.3".to_string(),
+                "https://mirror.example.com/packer".to_string()
+            )
+        );
+        assert!(matches!(insecure, Err(PackerError::ConfigError(_))));
+        assert!(matches!(bad_version, Err(PackerError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_packer_download_url() {
+        let url = |os, arch| {
+            release_platform(os, arch)
+                .map(|platform| packer_download_url(PACKER_RELEASES_URL, &platform, "1.10.3"))
+        };
+
+        assert_eq!(
+            url("linux", "x86_64").as_deref(),
+            Some("https://releases.hashicorp.com/packer/1.10.3/packer_1.10.3_linux_amd64.zip")
+        );
+        assert_eq!(
+            url("macos", "aarch64").as_deref(),
+            Some("https://releases.hashicorp.com/packer/1.10.3/packer_1.10.3_darwin_arm64.zip")
+        );
+        assert_eq!(
+            url("linux", "aarch64").as_deref(),
+            Some("https://releases.hashicorp.com/packer/1.10.3/packer_1.10.3_linux_arm64.zip")
+        );
+        assert_eq!(
+            url("linux", "arm").as_deref(),
+            Some("https://releases.hashicorp.com/packer/1.10.3/packer_1.10.3_linux_arm.zip")
+        );
+        assert_eq!(
+            url("windows", "x86_64").as_deref(),
+            Some("https://releases.hashicorp.com/packer/1.10.3/packer_1.10.3_windows_amd64.zip")
+        );
+        assert_eq!(url("linux", "riscv64"), None);
+        assert_eq!(url("ios", "aarch64"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_zip() {
+        use std::os::unix::fs::PermissionsExt;
+        use zip::write::SimpleFileOptions;
+
+        let test_dir = setup_test_env();
+        let zip_path = test_dir.path().join("packer.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+        writer
+            .start_file(
+                "packer",
+                SimpleFileOptions::default().unix_permissions(0o755),
+            )
+            .unwrap();
+        writer.write_all(b"#!/bin/sh\necho 1.7.8\n").unwrap();
+        writer
+            .start_file("LICENSE.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"MPL").unwrap();
+        writer.finish().unwrap();
+
+        let dest = test_dir.path().join("out");
+        extract_zip(&zip_path, &dest).unwrap();
+
+        let packer = dest.join("packer");
+        assert_eq!(
+            std::fs::read_to_string(&packer).unwrap(),
+            "#!/bin/sh\necho 1.7.8\n"
+        );
+        assert_ne!(packer.metadata().unwrap().permissions().mode() & 0o111, 0);
+        assert_eq!(
+            std::fs::read_to_string(dest.join("LICENSE.txt")).unwrap(),
+            "MPL"
+        );
+        assert!(is_packer_installed(&packer, "1.7.8"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_packer_from_cache() {
+        use zip::write::SimpleFileOptions;
+
+        let mut env = TestEnv::new();
+
+        // A release other than the pinned one is checked against PACKER_CHECKSUM
+        let version = "1.9.4";
+        let work_dir = setup_test_env();
+        let cache_dir = setup_test_env();
+        let platform = release_platform(std::env::consts::OS, std::env::consts::ARCH).unwrap();
+        let zip_url = packer_download_url(PACKER_RELEASES_URL, &platform, version);
+        let zip_name = zip_url.rsplit('/').next().unwrap();
+
+        let zip_path = cache_dir.path().join(zip_name);
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+        writer
+            .start_file(
+                "packer",
+                SimpleFileOptions::default().unix_permissions(0o755),
+            )
+            .unwrap();
+        writer
+            .write_all(format!("#!/bin/sh\necho {}\n", version).as_bytes())
+            .unwrap();
+        writer.finish().unwrap();
+        let checksum = sha256_hex(&std::fs::read(&zip_path).unwrap());
+
+        env.set_current_dir(work_dir.path());
+        env.set_var("PACKER_CACHE_PATH", cache_dir.path());
+        env.set_var("PACKER_BUILD_VERSION", version);
+        env.set_var("PACKER_CHECKSUM", &checksum);
+
+        let installed = install_packer();
+
+        let installed = installed.unwrap();
+        assert_eq!(
+            installed,
+            cache_dir.path().join(format!("packer-{}", version))
+        );
+        assert!(is_packer_installed(&installed, version));
+        // Nothing is written to the current directory
+        assert_eq!(std::fs::read_dir(work_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_release_checksum() {
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let config_error = |result: Result<String>| match result {
+            Err(PackerError::ConfigError(message)) => message,
+            other => panic!("expected ConfigError, got {:?}", other),
+        };
+
+        assert!(config_error(release_checksum(
+            PACKER_VERSION,
+            PACKER_RELEASES_URL,
+            "plan9_amd64",
+            None
+        ))
+        .contains("pinned"));
+        for (platform, pinned) in PACKER_CHECKSUMS {
+            assert_eq!(
+                release_checksum(PACKER_VERSION, PACKER_RELEASES_URL, platform, None).unwrap(),
+                *pinned
+            );
+        }
+
+        // A mirror or another version only installs with a checksum from the user
+        let mirror = "https://mirror.example.com/packer";
+        assert!(config_error(release_checksum(
+            PACKER_VERSION,
+            mirror,
+            "linux_amd64",
+            None
+        ))
+        .contains("PACKER_CHECKSUM"));
+        assert!(config_error(release_checksum(
+            "1.10.3",
+            PACKER_RELEASES_URL,
+            "linux_amd64",
+            None
+        ))
+        .contains("PACKER_CHECKSUM"));
+        assert_eq!(
+            release_checksum("1.10.3", mirror, "linux_amd64", Some(&abc.to_uppercase())).unwrap(),
+            abc
+        );
+        config_error(release_checksum(
+            "1.10.3",
+            mirror,
+            "linux_amd64",
+            Some("abc"),
+        ));
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_packer_cache_dir() {
+        let _guard = lock_env();
+
+        let original_xdg = std::env::var_os("XDG_CACHE_HOME");
+        let original_home = std::env::var_os("HOME");
+        std::env::set_var("XDG_CACHE_HOME", "/var/cache/me");
+        let xdg = packer_cache_dir();
+        std::env::set_var("XDG_CACHE_HOME", "relative");
+        std::env::set_var("HOME", "/home/me");
+        let home = packer_cache_dir();
+        std::env::remove_var("HOME");
+        let temp = packer_cache_dir();
+        std::env::set_var("PACKER_CACHE_PATH", "/tmp/packer-cache");
+        let overridden = packer_cache_dir();
+
+        std::env::remove_var("PACKER_CACHE_PATH");
+        for (name, value) in [("XDG_CACHE_HOME", original_xdg), ("HOME", original_home)] {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+
+        assert_eq!(xdg, Path::new("/var/cache/me/packer_rs"));
+        assert_eq!(home, Path::new("/home/me/.cache/packer_rs"));
+        assert_eq!(temp, std::env::temp_dir().join("packer_rs"));
+        assert_eq!(overridden, Path::new("/tmp/packer-cache"));
+    }
+
+    #[test]
+    fn test_pinned_checksums() {
+        // Without these the default install fails on the most common platforms
+        assert!(!PACKER_CHECKSUMS.is_empty());
+        for platform in ["linux_amd64", "darwin_amd64", "windows_amd64"] {
+            assert!(pinned_checksum(platform).is_some(), "{}", platform);
+        }
+        for (platform, checksum) in PACKER_CHECKSUMS {
+            assert_eq!(pinned_checksum(platform), Some(*checksum));
+            assert_eq!(checksum.len(), 64, "{}", platform);
+            assert!(checksum.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        }
+        assert_eq!(pinned_checksum("plan9_amd64"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_packer_reuses_versioned_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut env = TestEnv::new();
+
+        let cache_dir = setup_test_env();
+        let versioned = cache_dir.path().join(format!("packer-{}", PACKER_VERSION));
+        std::fs::write(&versioned, format!("#!/bin/sh\necho {}\n", PACKER_VERSION)).unwrap();
+        let mut perms = std::fs::metadata(&versioned).unwrap().permissions();
+        perms.set_mode(0o755);
+        std::fs::set_permissions(&versioned, perms).unwrap();
+
+        env.set_var("PACKER_CACHE_PATH", cache_dir.path());
+        // Nothing can be downloaded from here, so success means the
+        // versioned binary was reused
+        env.set_var("PACKER_DOWNLOAD_BASE_URL", "https://127.0.0.1:9");
+
+        let installed = install_packer();
+
+        assert_eq!(installed.unwrap(), versioned);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_new_rejects_non_executable_binary() {
+        let mut env = TestEnv::new();
+
+        let work_dir = setup_test_env();
+        let bin_dir = setup_test_env();
+        std::fs::write(work_dir.path().join("packer"), "#!/bin/sh\n").unwrap();
+        assert!(!is_executable(&work_dir.path().join("packer")));
+
+        env.set_current_dir(work_dir.path());
+        env.set_var("PATH", bin_dir.path());
+        env.set_var("PACKER_CACHE_PATH", bin_dir.path());
+        // Were the file skipped, this would turn into NotFound
+        env.set_var("PACKER_OFFLINE", "1");
+
+        let packer = Packer::new();
+
+        match packer {
+            Err(PackerError::ConfigError(message)) => assert!(message.contains("chmod +x")),
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(verify_checksum("abc.zip", b"abc", abc).is_ok());
+        assert!(verify_checksum("abc.zip", b"abc", &abc.to_uppercase()).is_ok());
+
+        match verify_checksum("abc.zip", b"abd", abc) {
+            Err(PackerError::ChecksumMismatch {
+                file,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(file, "abc.zip");
+                assert_eq!(expected, abc);
+                assert_ne!(actual, abc);
+            }
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_build_times() {
+        let output = "\
+1700000000,,ui,say,starting
+1700000000,amazon-ebs.base,ui,say,Creating AMI
+1700000095,amazon-ebs.base,artifact,0,id,us-east-1:ami-1
+1700000002,docker.app,ui,say,Pulling image
+1700000012,docker.app,ui,say,Committing
+1700000003,qemu.vm,ui,say,Booting
+1700000004,qemu.vm,timing,120.5
+";
+        let times = parse_build_times(output);
+        assert_eq!(times.len(), 3);
+        assert_eq!(times["amazon-ebs.base"], Duration::from_secs(95));
+        assert_eq!(times["docker.app"], Duration::from_secs(10));
+        assert_eq!(times["qemu.vm"], Duration::from_secs_f64(120.5));
+
+        let result = BuildResult {
+            build_times: times,
+            ..Default::default()
+        };
+        assert_eq!(
+            result.slowest_builder(),
+            Some(("qemu.vm", Duration::from_secs_f64(120.5)))
+        );
+        assert_eq!(BuildResult::default().slowest_builder(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_sequential() {
+        let test_dir = setup_test_env();
+        let log = test_dir.path().join("built.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!(
+                "for last; do :; done\necho \"$last\" >> '{}'\ncase \"$last\" in *bad*) exit 1;; esac",
+                log.display()
+            ),
+        ))
+        .unwrap();
+        let options = BuildOptions::default();
+        let built = || std::fs::read_to_string(&log).unwrap_or_default();
+
+        let results = packer
+            .build_sequential(&["base.pkr.hcl", "app.pkr.hcl"], &options)
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(built(), "base.pkr.hcl\napp.pkr.hcl\n");
+
+        std::fs::remove_file(&log).unwrap();
+        let templates = [
+            "base.pkr.hcl",
+            "bad1.pkr.hcl",
+            "app.pkr.hcl",
+            "bad2.pkr.hcl",
+        ];
+        let result = packer.build_sequential(&templates, &options);
+        assert_eq!(result.unwrap_err().exit_code(), Some(1));
+        assert_eq!(built(), "base.pkr.hcl\nbad1.pkr.hcl\n");
+
+        std::fs::remove_file(&log).unwrap();
+        let result = packer.build_sequential_with_policy(
+            &templates,
+            &options,
+            PartialFailurePolicy::CollectAll,
+        );
+        assert_eq!(built(), templates.map(|t| format!("{}\n", t)).concat());
+        match result {
+            Err(PackerError::MultipleErrors(errors)) => {
+                let labels: Vec<_> = errors.iter().map(|(label, _)| label.as_str()).collect();
+                assert_eq!(labels, ["bad1.pkr.hcl", "bad2.pkr.hcl"]);
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_parallel() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "for last; do :; done\n\
+             case \"$last\" in slow*) sleep 1;; esac\n\
+             case \"$last\" in *bad*) exit 1;; esac\n\
+             echo \"1,$last,artifact,0,id,$last\"",
+        ))
+        .unwrap();
+        let options = BuildOptions::default();
+
+        let started = Instant::now();
+        let results = packer
+            .build_parallel(&["slow1", "slow2", "fast"], &options, 3)
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1900));
+        let ids: Vec<_> = results
+            .iter()
+            .map(|result| result.artifacts[0].artifact_id.as_str())
+            .collect();
+        assert_eq!(ids, ["slow1", "slow2", "fast"]);
+
+        match packer.build_parallel(&["bad1", "fast", "bad2"], &options, 2) {
+            Err(PackerError::MultipleErrors(errors)) => {
+                let labels: Vec<_> = errors.iter().map(|(label, _)| label.as_str()).collect();
+                assert_eq!(labels, ["bad1", "bad2"]);
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
         }
+
+        assert!(matches!(
+            packer.build_parallel(&["fast"], &options, 0),
+            Err(PackerError::ConfigError(_))
+        ));
     }
-}
 
-impl Packer {
-    /// Create a new Packer instance
-    pub fn new() -> Result<Self> {
-        if !is_packer_installed() {
-            install_packer();
+    #[test]
+    fn test_find_templates() {
+        let test_dir = setup_test_env();
+        let root = test_dir.path();
+        std::fs::create_dir(root.join("nested")).unwrap();
+        for file in [
+            "web.pkr.hcl",
+            "db.pkr.json",
+            "variables.pkrvars.hcl",
+            "README.md",
+            "nested/app.pkr.hcl",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
         }
+        std::fs::write(
+            root.join("legacy.json"),
+            r#"{"builders": [{"type": "docker"}]}"#,
+        )
+        .unwrap();
+        std::fs::write(root.join("package.json"), r#"{"name": "site"}"#).unwrap();
 
-        let executable = if cfg!(target_os = "windows") {
-            PathBuf::from("./packer.exe")
-        } else {
-            PathBuf::from("./packer")
-        };
+        assert_eq!(
+            find_templates(root, false).unwrap(),
+            [
+                root.join("db.pkr.json"),
+                root.join("legacy.json"),
+                root.join("web.pkr.hcl")
+            ]
+        );
+        assert_eq!(
+            find_templates(root, true).unwrap(),
+            [
+                root.join("db.pkr.json"),
+                root.join("legacy.json"),
+                root.join("nested/app.pkr.hcl"),
+                root.join("web.pkr.hcl")
+            ]
+        );
+        assert!(matches!(
+            find_templates(&root.join("missing"), false),
+            Err(PackerError::IoError(_))
+        ));
+    }
 
-        if !executable.exists() {
-            return Err(PackerError::NotFound);
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_all() {
+        let test_dir = setup_test_env();
+        let templates = test_dir.path().join("templates");
+        std::fs::create_dir(&templates).unwrap();
+        for file in ["good.pkr.hcl", "bad.pkr.hcl"] {
+            std::fs::write(templates.join(file), "").unwrap();
         }
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            "for last; do :; done\n\
+             case \"$last\" in *bad*) echo 'Error: broken' >&2; exit 1;; esac\n\
+             echo 'Warning: Undefined -var variable'",
+        ))
+        .unwrap();
 
-        Ok(Self {
-            executable,
-            working_dir: None,
-        })
+        match packer.validate_all(&templates, false, &ValidateOptions::default()) {
+            Err(PackerError::MultipleErrors(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(
+                    errors[0].0,
+                    templates.join("bad.pkr.hcl").display().to_string()
+                );
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
+        }
+
+        std::fs::remove_file(templates.join("bad.pkr.hcl")).unwrap();
+        let results = packer
+            .validate_all(&templates, false, &ValidateOptions::default())
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[&templates.join("good.pkr.hcl")].warnings,
+            ["Undefined -var variable"]
+        );
+
+        let packer = packer.with_working_dir(test_dir.path());
+        let results = packer
+            .validate_dir("templates", &ValidateOptions::default())
+            .unwrap();
+        assert!(results.contains_key(&templates.join("good.pkr.hcl")));
     }
 
-    /// Set working directory for Packer commands
-    pub fn with_working_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
-        self.working_dir = Some(dir.into());
-        self
+    #[cfg(unix)]
+    #[test]
+    fn test_template_kind_skips_detection() {
+        let test_dir = setup_test_env();
+        std::fs::write(
+            test_dir.path().join("web.pkr.hcl"),
+            "source \"null\" \"a\" {}",
+        )
+        .unwrap();
+        let packer = Packer::new_with_path(write_mock_packer(test_dir.path(), ""))
+            .unwrap()
+            .with_working_dir(test_dir.path());
+
+        let detected = Template::detect(test_dir.path().join("web.pkr.hcl")).unwrap();
+        assert_eq!(detected.kind, Some(TemplateKind::Hcl2));
+        assert!(matches!(
+            packer.hcl2_upgrade(&detected, &Hcl2UpgradeOptions::default()),
+            Err(PackerError::ConfigError(_))
+        ));
+
+        // A kind given up front wins over what the file looks like
+        let inspection = packer
+            .inspect_parsed(Template::new("web.pkr.hcl").with_kind(TemplateKind::LegacyJson))
+            .unwrap();
+        assert_eq!(inspection.kind, Some(TemplateKind::LegacyJson));
+        assert_eq!(
+            packer.inspect_parsed("web.pkr.hcl").unwrap().kind,
+            Some(TemplateKind::Hcl2)
+        );
     }
 
-    /// Build images using a template
-    pub fn build<P: AsRef<std::path::Path>>(
-        &self,
-        template: P,
-        options: &BuildOptions,
-    ) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.arg("build");
+    #[test]
+    fn test_detect_template_kind() {
+        let test_dir = setup_test_env();
+        let root = test_dir.path();
+        std::fs::write(root.join("legacy.json"), "\n{\n  \"builders\": []\n}").unwrap();
+        std::fs::write(root.join("package.json"), r#"{"name": "site"}"#).unwrap();
 
-        if options.debug {
-            cmd.arg("-debug");
-        }
-        if options.force {
-            cmd.arg("-force");
+        let kind = |name: &str| detect_template_kind(&root.join(name));
+        assert_eq!(kind("web.pkr.hcl").unwrap(), TemplateKind::Hcl2);
+        assert_eq!(kind("web.pkr.json").unwrap(), TemplateKind::Hcl2);
+        assert_eq!(detect_template_kind(root).unwrap(), TemplateKind::Hcl2);
+        assert_eq!(kind("legacy.json").unwrap(), TemplateKind::LegacyJson);
+        assert!(matches!(
+            kind("package.json"),
+            Err(PackerError::ConfigError(_))
+        ));
+        assert!(matches!(kind("missing.json"), Err(PackerError::IoError(_))));
+        assert!(matches!(
+            kind("README.md"),
+            Err(PackerError::ConfigError(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hcl2_upgrade_rejects_hcl2_templates() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        match packer.hcl2_upgrade("web.pkr.hcl", &Hcl2UpgradeOptions::default()) {
+            Err(PackerError::ConfigError(message)) => assert_eq!(
+                message,
+                "web.pkr.hcl is already HCL2; hcl2_upgrade is only for legacy JSON templates"
+            ),
+            other => panic!("expected ConfigError, got {:?}", other),
         }
-        if let Some(parallel) = options.parallel_builds {
-            cmd.args(["-parallel-builds", &parallel.to_string()]);
+        assert!(!test_dir.path().join("args.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_dir() {
+        let test_dir = setup_test_env();
+        let templates = test_dir.path().join("templates");
+        std::fs::create_dir_all(templates.join("nested")).unwrap();
+        for file in [
+            "bad1.pkr.hcl",
+            "good.pkr.hcl",
+            "bad2.pkr.hcl",
+            "nested/bad3.pkr.hcl",
+        ] {
+            std::fs::write(templates.join(file), "").unwrap();
         }
-        if !options.color {
-            cmd.arg("-color=false");
+        let log = test_dir.path().join("validated.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            test_dir.path(),
+            &format!(
+                "for last; do :; done\necho \"$last\" >> '{}'\ncase \"$last\" in *bad*) exit 1;; esac",
+                log.display()
+            ),
+        ))
+        .unwrap();
+
+        match packer.validate_dir(&templates, &ValidateOptions::default()) {
+            Err(PackerError::MultipleErrors(errors)) => {
+                let labels: Vec<_> = errors.iter().map(|(label, _)| label.clone()).collect();
+                assert_eq!(
+                    labels,
+                    [
+                        templates.join("bad1.pkr.hcl").display().to_string(),
+                        templates.join("bad2.pkr.hcl").display().to_string()
+                    ]
+                );
+            }
+            other => panic!("expected MultipleErrors, got {:?}", other),
         }
-        if options.timestamp_ui {
-            cmd.arg("-timestamp-ui");
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packer_with_config() {
+        let test_dir = setup_test_env();
+        let mock = write_mock_packer(test_dir.path(), "exit 0");
+
+        let packer = Packer::with_config(PackerConfig {
+            executable_path: Some(mock.clone()),
+            working_dir: Some(test_dir.path().to_path_buf()),
+            env_vars: vec![("AWS_PROFILE".to_string(), "ci".to_string())],
+            log_level: Some(1),
+            cache_dir: Some(test_dir.path().to_path_buf()),
+            default_vars: vec![("region".to_string(), "us-west-2".to_string())],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(packer.executable, mock);
+        assert_eq!(packer.working_dir.as_deref(), Some(test_dir.path()));
+        assert!(packer
+            .env_vars
+            .contains(&("AWS_PROFILE".to_string(), "ci".to_string())));
+        assert!(packer
+            .env_vars
+            .contains(&("PACKER_LOG".to_string(), "1".to_string())));
+        assert!(packer
+            .env_vars
+            .contains(&("PKR_VAR_region".to_string(), "us-west-2".to_string())));
+
+        let missing_cache = PackerConfig {
+            executable_path: Some(mock),
+            cache_dir: Some(test_dir.path().join("missing")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Packer::with_config(missing_cache),
+            Err(PackerError::ConfigError(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_packer_config_from_toml_file() {
+        let _guard = lock_env();
+        let test_dir = setup_test_env();
+        let path = test_dir.path().join("packer-wrapper.toml");
+        std::fs::write(&path, PackerConfig::example_toml()).unwrap();
+
+        let config = PackerConfig::from_toml_file(&path).unwrap();
+        assert_eq!(
+            config,
+            PackerConfig {
+                executable_path: Some(PathBuf::from("/usr/local/bin/packer")),
+                working_dir: Some(PathBuf::from("./templates")),
+                env_vars: vec![("AWS_PROFILE".to_string(), "ci".to_string())],
+                log_level: Some(1),
+                log_path: Some(PathBuf::from("packer.log")),
+                cache_dir: Some(PathBuf::from(".packer.d/cache")),
+                tmp_dir: Some(PathBuf::from(".packer.d/tmp")),
+                default_vars: vec![("region".to_string(), "us-west-2".to_string())],
+                health_check_timeout_secs: Some(5),
+            }
+        );
+
+        std::env::set_var("PACKER_CONFIG", &path);
+        let from_env = PackerConfig::from_env();
+        std::env::remove_var("PACKER_CONFIG");
+        assert_eq!(from_env.unwrap(), config);
+        assert_eq!(PackerConfig::from_env().unwrap(), PackerConfig::default());
+
+        std::fs::write(&path, "log_level = \"loud\"\n").unwrap();
+        match PackerConfig::from_toml_file(&path) {
+            Err(PackerError::ConfigError(message)) => {
+                assert!(message.contains("packer-wrapper.toml:1:13"), "{}", message)
+            }
+            other => panic!("expected ConfigError, got {:?}", other),
         }
+    }
 
-        // Add variables
-        for (key, value) in &options.vars {
-            cmd.arg(format!("-var={}={}", key, value));
+    #[cfg(unix)]
+    #[test]
+    fn test_hcl2_upgrade_dir() {
+        let test_dir = setup_test_env();
+        let templates = test_dir.path().join("templates");
+        std::fs::create_dir(&templates).unwrap();
+        for name in ["a.json", "b.json", "c.json"] {
+            std::fs::write(templates.join(name), r#"{"builders": []}"#).unwrap();
         }
+        std::fs::write(templates.join("new.pkr.hcl"), "").unwrap();
+        std::fs::write(templates.join("vars.json"), r#"{"region": "us"}"#).unwrap();
 
-        // Add var files
-        for var_file in &options.var_files {
-            cmd.arg(format!("-var-file={}", var_file.display()));
+        // Writes `-output-file` like packer does, failing for b.json unless allowed
+        let packer = Packer::new_with_path(write_current_mock_packer(
+            test_dir.path(),
+            "for arg; do case \"$arg\" in -output-file=*) out=${arg#-output-file=};; esac; done\n\
+             for last; do :; done\n\
+             case \"$last\" in *b.json) [ -e \"$last.ok\" ] || exit 1;; esac\n\
+             echo converted > \"$out\"",
+        ))
+        .unwrap();
+        let options = Hcl2UpgradeOptions::default();
+
+        match packer.hcl2_upgrade_dir(&templates, &options) {
+            Err(PackerError::PartialUpgrade(partial)) => {
+                assert_eq!(
+                    partial.succeeded,
+                    [(templates.join("a.json"), templates.join("a.pkr.hcl"))]
+                );
+                assert_eq!(partial.failed_template, templates.join("b.json"));
+                assert_eq!(partial.error.exit_code(), Some(1));
+            }
+            other => panic!("expected PartialUpgrade, got {:?}", other),
         }
+        assert!(!templates.join("c.pkr.hcl").exists());
 
-        cmd.arg(template.as_ref());
+        std::fs::write(templates.join("b.json.ok"), "").unwrap();
+        let upgraded = packer.hcl2_upgrade_dir(&templates, &options).unwrap();
+        let outputs: Vec<_> = upgraded.iter().map(|(_, output)| output.clone()).collect();
+        assert_eq!(
+            outputs,
+            ["a", "b", "c"].map(|name| templates.join(format!("{}.pkr.hcl", name)))
+        );
+        assert_eq!(
+            std::fs::read_to_string(templates.join("c.pkr.hcl")).unwrap(),
+            "converted\n"
+        );
 
-        self.execute_command(cmd)
+        let packer = packer.with_working_dir(test_dir.path());
+        let upgraded = packer.hcl2_upgrade_dir("templates", &options).unwrap();
+        assert_eq!(
+            upgraded[0],
+            (templates.join("a.json"), templates.join("a.pkr.hcl"))
+        );
     }
 
-    /// Initialize a new Packer configuration
-    pub fn init<P: AsRef<std::path::Path>>(&self, template: P) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.arg("init").arg(template.as_ref());
-        self.execute_command(cmd)
+    #[cfg(unix)]
+    #[test]
+    fn test_fix_in_place() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "if [ \"$2\" = empty.json ]; then exit 0; fi\necho '{\"fixed\": true}'",
+        );
+        std::fs::write(dir.path().join("old.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("empty.json"), "{}").unwrap();
+        let packer = Packer::new_with_path(executable)
+            .unwrap()
+            .with_working_dir(dir.path());
+
+        packer.fix_in_place("old.json").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("old.json")).unwrap(),
+            "{\"fixed\": true}\n"
+        );
+        assert!(!dir.path().join("old.json.tmp").exists());
+
+        assert!(packer.fix_in_place("empty.json").is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("empty.json")).unwrap(),
+            "{}"
+        );
     }
 
-    /// Validate a Packer template
-    pub fn validate<P: AsRef<std::path::Path>>(&self, template: P) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.arg("validate").arg(template.as_ref());
-        self.execute_command(cmd)
+    #[cfg(unix)]
+    #[test]
+    fn test_build_keeps_provisioner_log() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "echo '==> docker: Provisioning with shell script' >&2\nfor last; do :; done\n[ \"$last\" = ok.pkr.hcl ]",
+        );
+        let packer = Packer::new_with_path(executable).unwrap();
+
+        let result = packer
+            .build("ok.pkr.hcl", &BuildOptions::default())
+            .unwrap();
+        assert_eq!(
+            result.provisioner_log,
+            "==> docker: Provisioning with shell script\n"
+        );
+
+        match packer.build("broken.pkr.hcl", &BuildOptions::default()) {
+            Err(PackerError::ExecutionError { stderr, .. }) => {
+                assert_eq!(stderr, result.provisioner_log)
+            }
+            other => panic!("expected ExecutionError, got {:?}", other),
+        }
     }
 
-    /// Inspect a template
-    pub fn inspect<P: AsRef<std::path::Path>>(&self, template: P) -> Result<String> {
-        let mut cmd = self.base_command();
-        cmd.arg("inspect").arg(template.as_ref());
-        let output = cmd.output()?;
+    #[cfg(unix)]
+    #[test]
+    fn test_build_options_validate() {
+        // Relative var files are resolved against the current directory
+        let _guard = lock_env();
+        let test_dir = setup_test_env();
+        std::fs::write(test_dir.path().join("dev.pkrvars.hcl"), "").unwrap();
 
-        if !output.status.success() {
-            return Err(PackerError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+        let mut builder = BuildOptionsBuilder::default();
+        builder.vars([("", "us-west-2")]);
+        assert!(matches!(
+            builder.build_and_validate(),
+            Err(PackerError::ConfigError(_))
+        ));
+
+        let options = BuildOptionsBuilder::default()
+            .var_files(vec![PathBuf::from("dev.pkrvars.hcl")])
+            .build_and_validate();
+        match options {
+            Err(PackerError::ConfigError(message)) => assert!(message.contains("dev.pkrvars.hcl")),
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+
+        let options = BuildOptions {
+            var_files: vec![PathBuf::from("dev.pkrvars.hcl")],
+            ..Default::default()
+        };
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+        for result in [
+            packer.build("template.pkr.hcl", &options),
+            packer.build_with_output("template.pkr.hcl", &options, |_| {}),
+            packer.build_with_sink("template.pkr.hcl", &options, &mut LoggingEventSink),
+            packer.build_with_timeout("template.pkr.hcl", &options, Duration::from_secs(10)),
+        ] {
+            assert!(matches!(result, Err(PackerError::ConfigError(_))));
         }
+        assert!(!test_dir.path().join("args.txt").exists());
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        let packer = packer.with_working_dir(test_dir.path());
+        packer.build("template.pkr.hcl", &options).unwrap();
     }
 
-    /// Fix template
-    pub fn fix<P: AsRef<std::path::Path>>(&self, template: P) -> Result<String> {
-        let mut cmd = self.base_command();
-        cmd.arg("fix").arg(template.as_ref());
-        let output = cmd.output()?;
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_build_async_validates_options() {
+        use tokio_stream::StreamExt;
 
-        if !output.status.success() {
-            return Err(PackerError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
-        }
+        let test_dir = setup_test_env();
+        let options = BuildOptions {
+            var_files: vec![PathBuf::from("dev.pkrvars.hcl")],
+            ..Default::default()
+        };
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        assert!(matches!(
+            packer.build_async("template.pkr.hcl", &options).await,
+            Err(PackerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            packer
+                .build_cancellable("template.pkr.hcl", &options, CancellationToken::new())
+                .await,
+            Err(PackerError::ConfigError(_))
+        ));
+        let stream = packer.build_stream("template.pkr.hcl", &options);
+        tokio::pin!(stream);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(PackerError::ConfigError(_)))
+        ));
+        assert!(!test_dir.path().join("args.txt").exists());
     }
 
-    /// Get version information
-    pub fn version(&self) -> Result<String> {
-        let mut cmd = self.base_command();
-        cmd.arg("version");
-        let output = cmd.output()?;
+    #[cfg(unix)]
+    #[test]
+    fn test_build_with_machine_readable_log_file() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "echo '1700000000,docker.app,ui,say,==> docker.app: Starting'\n\
+             echo '1700000005,docker.app,ui,say,==> docker.app: Provisioning'\n\
+             echo '1700000040,docker.app,artifact,0,id,sha256:abc'\n\
+             echo '1700000042,docker.app,ui,say,Build finished'",
+        );
+        let packer = Packer::new_with_path(executable)
+            .unwrap()
+            .with_working_dir(dir.path());
+        let options = BuildOptionsBuilder::default()
+            .machine_readable_log_file("build.log")
+            .build()
+            .unwrap();
 
-        if !output.status.success() {
-            return Err(PackerError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+        let mut lines = 0;
+        let results = [
+            packer.build("template.pkr.hcl", &options).unwrap(),
+            packer
+                .build_with_output("template.pkr.hcl", &options, |_| lines += 1)
+                .unwrap(),
+            packer
+                .build_with_timeout("template.pkr.hcl", &options, Duration::from_secs(10))
+                .unwrap(),
+        ];
+        assert_eq!(lines, 4);
+
+        for result in results {
+            assert_eq!(result.artifacts[0].artifact_id, "sha256:abc");
+            assert_eq!(result.build_times["docker.app"], Duration::from_secs(42));
         }
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        let log = std::fs::read_to_string(dir.path().join("build.log")).unwrap();
+        assert_eq!(log.lines().count(), 4);
+        assert!(log.contains("Provisioning"));
     }
 
-    /// Create a base command with common configuration
-    fn base_command(&self) -> Command {
-        let mut cmd = Command::new(&self.executable);
-        if let Some(dir) = &self.working_dir {
-            cmd.current_dir(dir);
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn test_build_async_with_machine_readable_log_file() {
+        use tokio_stream::StreamExt;
+
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "echo '1700000000,docker.app,ui,say,Provisioning'\n\
+             echo '1700000040,docker.app,artifact,0,id,sha256:abc'",
+        );
+        let packer = Packer::new_with_path(executable)
+            .unwrap()
+            .with_working_dir(dir.path());
+        let options = BuildOptionsBuilder::default()
+            .machine_readable_log_file("build.log")
+            .build()
+            .unwrap();
+
+        let log = dir.path().join("build.log");
+        let async_result = packer
+            .build_async("template.pkr.hcl", &options)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 2);
+        std::fs::remove_file(&log).unwrap();
+        let cancellable_result = packer
+            .build_cancellable("template.pkr.hcl", &options, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 2);
+        for result in [async_result, cancellable_result] {
+            assert_eq!(result.artifacts[0].artifact_id, "sha256:abc");
         }
-        cmd
+
+        let stream = packer.build_stream("template.pkr.hcl", &options);
+        tokio::pin!(stream);
+        match stream.next().await {
+            Some(Err(PackerError::ConfigError(_))) => {}
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+        assert!(stream.next().await.is_none());
     }
 
-    /// Execute a command and handle its result
-    fn execute_command(&self, mut cmd: Command) -> Result<()> {
-        let status = cmd.status()?;
+    #[cfg(unix)]
+    #[test]
+    fn test_execution_error_keeps_end_of_stderr() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "i=0\nwhile [ $i -lt 500 ]; do echo \"noise $i\" >&2; i=$((i + 1)); done\necho 'Error: plugin not found' >&2\nexit 1",
+        );
+        let packer = Packer::new_with_path(executable)
+            .unwrap()
+            .with_stderr_limit(64)
+            .with_stderr_writer(Box::new(std::io::sink()));
 
-        if !status.success() {
-            return Err(PackerError::ExecutionError(format!(
-                "Command failed with exit code: {}",
-                status
-            )));
+        for result in [
+            packer
+                .build("template.pkr.hcl", &BuildOptions::default())
+                .map(drop),
+            packer.plugins().install("github.com/hashicorp/docker"),
+        ] {
+            match result {
+                Err(PackerError::ExecutionError {
+                    stderr, message, ..
+                }) => {
+                    assert!(!stderr.contains("noise 0\n"), "{}", stderr);
+                    assert!(stderr.ends_with("noise 499\nError: plugin not found\n"));
+                    assert!(stderr.len() < 100);
+                    assert!(message.ends_with("Error: plugin not found"));
+                }
+                other => panic!("expected ExecutionError, got {:?}", other),
+            }
         }
+    }
 
-        Ok(())
+    #[cfg(unix)]
+    #[test]
+    fn test_inspect_with_vars() {
+        let dir = setup_test_env();
+        let executable = write_recording_packer(dir.path());
+        let packer = Packer::new_with_path(executable).unwrap();
+
+        packer
+            .inspect_with_vars(
+                "template.pkr.hcl",
+                &[("region".to_string(), "eu-west-1".to_string())],
+                &["prod.pkrvars.hcl".into()],
+            )
+            .unwrap();
+        assert_eq!(
+            recorded_args(dir.path()),
+            [
+                "inspect",
+                "-var",
+                "region=eu-west-1",
+                "-var-file=prod.pkrvars.hcl",
+                "template.pkr.hcl"
+            ]
+        );
+
+        packer.inspect("template.pkr.hcl").unwrap();
+        assert_eq!(recorded_args(dir.path()), ["inspect", "template.pkr.hcl"]);
     }
-}
 
-// Plugin management functionality
-impl Packer {
-    /// Install a Packer plugin
-    pub fn plugin_install(&self, plugin_name: &str) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.args(["plugin", "install", plugin_name]);
-        self.execute_command(cmd)
+    #[cfg(unix)]
+    #[test]
+    fn test_inspect_cache() {
+        let dir = setup_test_env();
+        let calls = dir.path().join("calls.txt");
+        let packer = Packer::new_with_path(write_mock_packer(
+            dir.path(),
+            &format!(
+                "echo inspect >> '{}'\nprintf 'Builders:\\n  docker.app\\n'",
+                calls.display()
+            ),
+        ))
+        .unwrap()
+        .with_working_dir(dir.path())
+        .with_inspect_cache();
+        let template = dir.path().join("template.pkr.hcl");
+        std::fs::write(&template, "source \"docker\" \"app\" {}\n").unwrap();
+        let call_count = || std::fs::read_to_string(&calls).unwrap().lines().count();
+
+        let inspection = packer.inspect_parsed("template.pkr.hcl").unwrap();
+        assert_eq!(inspection.builders.len(), 1);
+        assert_eq!(
+            packer.clone().inspect_parsed("template.pkr.hcl").unwrap(),
+            inspection
+        );
+        assert_eq!(call_count(), 1);
+
+        std::fs::File::options()
+            .write(true)
+            .open(&template)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        packer.inspect_parsed("template.pkr.hcl").unwrap();
+        assert_eq!(call_count(), 2);
+
+        // Only plain inspections are cached
+        packer
+            .inspect_with_vars("template.pkr.hcl", &[], &[])
+            .unwrap();
+        packer.inspect_parsed(".").unwrap();
+        assert_eq!(call_count(), 4);
     }
 
-    /// Remove a Packer plugin
-    pub fn plugin_remove(&self, plugin_name: &str) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.args(["plugin", "remove", plugin_name]);
-        self.execute_command(cmd)
+    #[cfg(unix)]
+    #[test]
+    fn test_build_respects_packer_no_color() {
+        let dir = setup_test_env();
+        let executable = write_recording_packer(dir.path());
+        let packer = Packer::new_with_path(executable).unwrap();
+        let colored = BuildOptions::default();
+
+        packer.build("template.pkr.hcl", &colored).unwrap();
+        assert!(!recorded_args(dir.path()).contains(&"-color=false".to_string()));
+
+        let no_color = packer.clone().with_env("PACKER_NO_COLOR", "1");
+        no_color.build("template.pkr.hcl", &colored).unwrap();
+        assert!(recorded_args(dir.path()).contains(&"-color=false".to_string()));
+
+        let cleared = no_color.clone().with_env("PACKER_NO_COLOR", "");
+        cleared.build("template.pkr.hcl", &colored).unwrap();
+        assert!(!recorded_args(dir.path()).contains(&"-color=false".to_string()));
+
+        // An explicit choice beats the environment either way
+        let forced = BuildOptionsBuilder::default().color(true).build().unwrap();
+        no_color.build("template.pkr.hcl", &forced).unwrap();
+        assert!(!recorded_args(dir.path()).contains(&"-color=false".to_string()));
+        let cmd = no_color
+            .build_command(Path::new("template.pkr.hcl"), &forced)
+            .unwrap();
+        assert!(cmd
+            .get_envs()
+            .any(|(key, value)| key == "PACKER_NO_COLOR" && value.is_none()));
+
+        let plain = BuildOptionsBuilder::default().color(false).build().unwrap();
+        packer.build("template.pkr.hcl", &plain).unwrap();
+        assert!(recorded_args(dir.path()).contains(&"-color=false".to_string()));
     }
 
-    /// List installed plugins
-    pub fn plugin_list(&self) -> Result<String> {
-        let mut cmd = self.base_command();
-        cmd.args(["plugin", "list"]);
-        let output = cmd.output()?;
+    #[derive(Default)]
+    struct RecordingSink(Vec<String>);
 
-        if !output.status.success() {
-            return Err(PackerError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+    impl BuildEventSink for RecordingSink {
+        fn on_start(&mut self, builder: &str) {
+            self.0.push(format!("start {}", builder));
+        }
+
+        fn on_provisioner(&mut self, step: u32, description: &str) {
+            self.0.push(format!("provisioner {} {}", step, description));
+        }
+
+        fn on_artifact(&mut self, artifact: &BuildArtifact) {
+            self.0.push(format!("artifact {}", artifact.artifact_id));
+        }
+
+        fn on_complete(&mut self, builder: &str, duration: Duration) {
+            self.0.push(format!("complete {} {:?}", builder, duration));
         }
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        fn on_error(&mut self, builder: &str, message: &str) {
+            self.0.push(format!("error {} {}", builder, message));
+        }
     }
-}
 
-// Console functionality
-impl Packer {
-    /// Start Packer console
-    pub fn console<P: AsRef<std::path::Path>>(&self, template: P) -> Result<()> {
-        let mut cmd = self.base_command();
-        cmd.arg("console").arg(template.as_ref());
-        self.execute_command(cmd)
+    #[cfg(unix)]
+    #[test]
+    fn test_build_with_sink() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(
+            dir.path(),
+            "echo '100,,ui,say,Starting builds'\n\
+             echo '100,docker.app,ui,say,==> docker.app: Creating a temporary directory'\n\
+             echo '101,qemu.vm,ui,say,==> qemu.vm: Provisioning with shell script: a.sh'\n\
+             echo '102,docker.app,ui,say,==> docker.app: Provisioning with shell script: b.sh'\n\
+             echo '103,qemu.vm,error,disk full'\n\
+             echo '104,docker.app,ui,say,==> docker.app: Provisioning with ansible'\n\
+             echo '105,docker.app,artifact,0,builder-id,packer.docker'\n\
+             echo '105,docker.app,artifact,0,id,sha256:abc'\n\
+             echo '105,docker.app,artifact,0,end'\n\
+             exit 1",
+        );
+        let packer = Packer::new_with_path(executable).unwrap();
+
+        let mut sink = RecordingSink::default();
+        let result =
+            packer.build_with_sink("template.pkr.hcl", &BuildOptions::default(), &mut sink);
+        assert!(result.is_err());
+        assert_eq!(
+            sink.0,
+            [
+                "start docker.app",
+                "start qemu.vm",
+                "provisioner 1 ==> qemu.vm: Provisioning with shell script: a.sh",
+                "provisioner 1 ==> docker.app: Provisioning with shell script: b.sh",
+                "error qemu.vm disk full",
+                "provisioner 2 ==> docker.app: Provisioning with ansible",
+                "artifact sha256:abc",
+                "complete docker.app 5s",
+            ]
+        );
     }
-}
 
-// HCL2 upgrade functionality
-impl Packer {
-    /// Upgrade HCL2 configuration
-    pub fn hcl2_upgrade<P: AsRef<std::path::Path>>(&self, template: P) -> Result<String> {
-        let mut cmd = self.base_command();
-        cmd.arg("hcl2_upgrade").arg(template.as_ref());
-        let output = cmd.output()?;
+    #[cfg(unix)]
+    #[test]
+    fn test_check_health() {
+        let dir = setup_test_env();
+        let executable = write_mock_packer(dir.path(), "echo 'Packer v1.9.4'");
+        let packer = Packer::new_with_path(executable).unwrap();
+        assert_eq!(packer.check_health().unwrap(), PackerVersion::new(1, 9, 4));
 
-        if !output.status.success() {
-            return Err(PackerError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+        let hung = write_mock_packer(dir.path(), "sleep 5");
+        let packer = Packer::new_with_path(hung)
+            .unwrap()
+            .with_health_check_timeout(Duration::from_millis(200));
+        match packer.check_health() {
+            Err(PackerError::TimeoutError { .. }) => {}
+            other => panic!("expected TimeoutError, got {:?}", other),
         }
+    }
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    #[test]
+    #[ignore = "needs a real packer binary"]
+    fn test_check_health_smoke() {
+        let packer = Packer::new().unwrap();
+        let version = packer.check_health().unwrap();
+        assert!(version >= PackerVersion::new(1, 0, 0));
     }
-}
 
-fn is_packer_installed() -> bool {
-    let packer_executable = if cfg!(target_os = "windows") {
-        "./packer.exe"
-    } else {
-        "./packer"
-    };
+    #[test]
+    fn test_build_options_display() {
+        let mut options = BuildOptions {
+            debug: true,
+            force: true,
+            parallel_builds: Some(2),
+            var_files: vec!["my vars.pkrvars.hcl".into()],
+            ..Default::default()
+        };
+        options
+            .add_var("region", "us-east-1")
+            .add_var("ami_name", "base image")
+            .add_var("owner", "it's me")
+            .add_secret_var("token", "hunter2");
 
-    Command::new(packer_executable)
-        .arg("--version")
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false)
-}
+        assert_eq!(
+            options.to_string(),
+            "-debug -force -parallel-builds 2 -var region=us-east-1 -var 'ami_name=base image' \
+             -var 'owner=it'\\''s me' -var 'token=***' '-var-file=my vars.pkrvars.hcl'"
+        );
+        assert_eq!(options.to_args()[11], "token=hunter2");
+        assert_eq!(BuildOptions::default().to_string(), "");
+    }
 
-fn install_packer() {
-    let target_os = std::env::consts::OS;
-    // build_target::target_os().expect("Failed to get currentOS");
+    #[test]
+    fn test_mock_process_launcher() {
+        let dir = setup_test_env();
+        let executable = dir.path().join("packer");
+        std::fs::write(&executable, "").unwrap();
 
-    match target_os {
-        "windows" => {
-            Command::new("powershell")
-                .arg("-Command")
-                .arg("Invoke-WebRequest -Uri https://releases.hashicorp.com/packer/1.7.8/packer_1.7.8_windows_amd64.zip -OutFile packer.zip")
-                .status()
-                .expect("Failed to install Packer on Windows");
-        }
-        "macos" => {
-            Command::new("sh")
-                .arg("-c")
-                .arg("curl -o packer.zip https://releases.hashicorp.com/packer/1.7.8/packer_1.7.8_darwin_amd64.zip")
-                .status()
-                .expect("Failed to install Packer on macOS");
-        }
-        "linux" => {
-            Command::new("sh")
-                .arg("-c")
-                .arg("curl -o packer.zip https://releases.hashicorp.com/packer/1.7.8/packer_1.7.8_linux_amd64.zip")
-                .status()
-                .expect("Failed to install Packer on Linux");
+        let launcher = MockProcessLauncher::new(
+            ["build", "-machine-readable", "-force", "template.pkr.hcl"],
+            "1700000000,docker.app,artifact,0,id,sha256:abc\n",
+        );
+        let packer = Packer::new_with_path(&executable)
+            .unwrap()
+            .with_launcher(launcher);
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+
+        let result = packer.build("template.pkr.hcl", &options).unwrap();
+        assert_eq!(result.artifacts[0].artifact_id, "sha256:abc");
+
+        match packer.build("other.pkr.hcl", &options) {
+            Err(PackerError::IoError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput)
+            }
+            other => panic!("expected IoError, got {:?}", other),
         }
-        _ => panic!("Unsupported OS"),
     }
-    let packer_zip = std::fs::read("packer.zip").expect("Failed to read packer.zip");
-    zip_extract::extract(Cursor::new(packer_zip), Path::new("."), true).expect("Failed to extract packer.zip");
-}
 
-#[cfg(test)]
-mod tests {
+    #[cfg(feature = "test-support")]
+    #[test]
+    fn test_mock_packer() {
+        let mock = MockPacker::new();
+        assert_eq!(mock.last_build_template(), None);
 
-    use super::*;
-    use tempfile::TempDir;
+        mock.push_build_result(Err(PackerError::ConfigError("boom".into())));
+        assert!(mock.build("a.pkr.hcl", &BuildOptions::default()).is_err());
+        mock.validate("b.pkr.hcl", &ValidateOptions::default())
+            .unwrap();
+        mock.build("c.pkr.hcl", &BuildOptions::default()).unwrap();
 
-    // Helper function to create a test environment
-    fn setup_test_env() -> TempDir {
-        tempfile::tempdir().unwrap()
+        assert_eq!(mock.build_call_count(), 2);
+        assert_eq!(mock.last_build_template(), Some(PathBuf::from("c.pkr.hcl")));
+        assert_eq!(mock.calls()[1].command, "validate");
     }
 
+    #[cfg(any(feature = "tracing", feature = "logging"))]
     #[test]
-    fn test_build_options_builder() {
-        let options = BuildOptionsBuilder::default()
-            .debug(true)
-            .force(true)
-            .parallel_builds(Some(2))
-            .vars(vec![("key".to_string(), "value".to_string())])
-            .build()
-            .unwrap();
-
-        assert!(options.debug);
-        assert!(options.force);
-        assert_eq!(options.parallel_builds, Some(2));
-        assert_eq!(options.vars.len(), 1);
-        assert_eq!(options.vars[0].0, "key");
-        assert_eq!(options.vars[0].1, "value");
+    fn test_redact_args() {
+        let args = [
+            "build",
+            "-force",
+            "-var",
+            "token=hunter2",
+            "-var-file=a.pkrvars.hcl",
+            "t.pkr.hcl",
+        ]
+        .map(String::from);
+        assert_eq!(
+            redact_args(&args),
+            "build -force -var token=*** -var-file=a.pkrvars.hcl t.pkr.hcl"
+        );
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_packer_new_not_found() {
-        // Create a clean test directory
-        let test_dir = setup_test_env();
-        println!("{test_dir:#?}");
+    fn test_build_forwards_sigterm() {
+        // SIGTERM sent to the test process would reach concurrent tests'
+        // builds too, so the test body runs alone in a copy of the test binary
+        if std::env::var_os("PACKER_RS_SIGTERM_TEST").is_none() {
+            let output = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::test_build_forwards_sigterm"])
+                .args(["--test-threads=1", "--nocapture"])
+                .env("PACKER_RS_SIGTERM_TEST", "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(
+                output.status.success() && stdout.contains("1 passed"),
+                "{}{}",
+                stdout,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return;
+        }
 
-        // Save current dir and change to test dir
-        let original_dir = std::env::current_dir().unwrap();
-        std::env::set_current_dir(test_dir.path()).unwrap();
+        let dir = setup_test_env();
+        let cleaned = dir.path().join("cleaned");
+        let executable = write_mock_packer(
+            dir.path(),
+            &format!(
+                "trap 'echo done > {}; exit 1' TERM\nsleep 5 &\nwait",
+                cleaned.display()
+            ),
+        );
+        let packer = Packer::new_with_path(executable).unwrap();
 
-        // Now we know for sure there's no packer executable here
-        let packer = Packer::new();
-        assert!(packer.is_ok());
+        // Catch SIGTERM ourselves, so raising it again afterwards is harmless. Not
+        // SIG_IGN: packer would inherit that and be unable to trap the signal.
+        extern "C" fn ignore(_: libc::c_int) {}
+        // SAFETY: `ignore` does nothing, so it is trivially async-signal-safe.
+        unsafe {
+            libc::signal(
+                libc::SIGTERM,
+                ignore as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+        let sender = std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(300));
+            // SAFETY: kill has no memory-safety preconditions.
+            unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+        });
+        let started = Instant::now();
+        let result = packer.build("template.pkr.hcl", &BuildOptions::default());
+        sender.join().unwrap();
+        // SAFETY: as above.
+        unsafe { libc::signal(libc::SIGTERM, libc::SIG_DFL) };
 
-        // Change back to original directory
-        std::env::set_current_dir(original_dir).unwrap();
+        assert!(result.is_err());
+        assert!(cleaned.exists());
+        assert!(started.elapsed() < Duration::from_secs(4));
     }
 
     #[test]
-    fn test_packer_with_working_dir() {
-        let test_dir = setup_test_env();
-        let packer = Packer {
-            executable: PathBuf::from("dummy"),
-            working_dir: None,
-        }
-        .with_working_dir(test_dir.path());
+    fn test_secret_vars_are_redacted() {
+        let mut options = BuildOptions::default();
+        options.add_secret_var("api_key", "hunter2");
+        options
+            .vars
+            .push(("region".to_string(), "us-west-2".into()));
 
-        assert_eq!(packer.working_dir.unwrap(), test_dir.path());
+        let debug = format!("{:?}", options);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("SecretVar(\"***\")"));
+        assert!(debug.contains("us-west-2"));
+
+        assert_eq!(options.vars[0].1.to_string(), "***");
+        assert_eq!(options.vars[0].1.expose(), "hunter2");
+
+        let redacted = options.redacted_clone();
+        assert_eq!(redacted.vars[0].0, "api_key");
+        assert_eq!(redacted.vars[0].1.expose(), "[REDACTED]");
+        assert_eq!(redacted.vars[1].1.expose(), "us-west-2");
+        assert_eq!(options.clone().vars[0].1.expose(), "hunter2");
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_build_options_default() {
-        let options = BuildOptions::default();
-        assert!(!options.debug);
-        assert!(!options.force);
-        assert!(options.color);
-        assert!(options.vars.is_empty());
-        assert!(options.var_files.is_empty());
-        assert_eq!(options.parallel_builds, None);
+    fn test_secret_vars_reach_packer() {
+        let test_dir = setup_test_env();
+        let packer = Packer::new_with_path(write_recording_packer(test_dir.path())).unwrap();
+
+        let mut options = BuildOptions::default();
+        options
+            .add_secret_var("api_key", "hunter2")
+            .add_var("token", "c2VjcmV0==");
+        packer.build("template.pkr.hcl", &options).unwrap();
+
+        // Each value is its own argument, so `=` inside it is passed through as is
+        let args = recorded_args(test_dir.path());
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-var", "api_key=hunter2"]));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-var", "token=c2VjcmV0=="]));
     }
 
     #[test]
     fn test_build_command_construction() {
-        let packer = Packer {
-            executable: PathBuf::from("dummy"),
-            working_dir: None,
-        };
+        let packer = dummy_packer();
 
         let _options = BuildOptionsBuilder::default()
             .debug(true)